@@ -0,0 +1,21 @@
+#![no_main]
+#![allow(dead_code)]
+
+include!("../../src/bin/broadcast/main.rs");
+
+libfuzzer_sys::fuzz_target!(|data: &[u8]| {
+    maelstrom_fuzz::check::<InPayload, _, _>(
+        data,
+        |input, writer| maelstrom::run_node::<BroadcastNode<_>, _, _, _>(input, writer),
+        |msg| match msg.body.payload {
+            InPayload::Gossip { messages: None, .. }
+            | InPayload::GossipOk { .. }
+            | InPayload::Reconcile(_)
+            | InPayload::Graft { .. }
+            | InPayload::Prune
+            | InPayload::Sequence { .. } => None,
+            _ => maelstrom_fuzz::reply_to_sender(msg),
+        },
+        None,
+    );
+});
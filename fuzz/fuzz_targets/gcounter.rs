@@ -0,0 +1,20 @@
+#![no_main]
+#![allow(dead_code)]
+
+include!("../../src/bin/gcounter.rs");
+
+libfuzzer_sys::fuzz_target!(|data: &[u8]| {
+    maelstrom_fuzz::check::<InPayload, _, _>(
+        data,
+        |input, writer| maelstrom::run_node::<CounterNode<_>, _, _, _>(input, writer),
+        |msg| match msg.body.payload {
+            InPayload::Broadcast { .. }
+            | InPayload::ReadOk { .. }
+            | InPayload::WriteOk
+            | InPayload::CasOk
+            | InPayload::Error { .. } => None,
+            _ => maelstrom_fuzz::reply_to_sender(msg),
+        },
+        None,
+    );
+});
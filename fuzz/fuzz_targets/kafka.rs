@@ -0,0 +1,27 @@
+#![no_main]
+#![allow(dead_code)]
+
+include!("../../src/bin/kafka.rs");
+
+// n0 is the leader, so requests forwarded by a follower are answered to the original client
+fn reply_to(msg: &maelstrom::InMessage<InPayload>) -> Option<(String, usize)> {
+    let client_info = match &msg.body.payload {
+        InPayload::Send { client_info, .. }
+        | InPayload::Poll { client_info, .. }
+        | InPayload::CommitOffsets { client_info, .. }
+        | InPayload::ListCommittedOffsets { client_info, .. } => client_info,
+    };
+    match client_info {
+        Some(ClientInfo { client_id, msg_id }) => msg_id.map(|msg_id| (client_id.clone(), msg_id)),
+        None => maelstrom_fuzz::reply_to_sender(msg),
+    }
+}
+
+libfuzzer_sys::fuzz_target!(|data: &[u8]| {
+    maelstrom_fuzz::check::<InPayload, _, _>(
+        data,
+        |input, writer| maelstrom::run_node::<KafkaNode<_>, _, _, _>(input, writer),
+        reply_to,
+        Some(1),
+    );
+});
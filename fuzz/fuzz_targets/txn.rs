@@ -0,0 +1,13 @@
+#![no_main]
+#![allow(dead_code)]
+
+include!("../../src/bin/txn.rs");
+
+libfuzzer_sys::fuzz_target!(|data: &[u8]| {
+    maelstrom_fuzz::check::<InPayload, _, _>(
+        data,
+        |input, writer| maelstrom::run_node::<TxnNode<_>, _, _, _>(input, writer),
+        maelstrom_fuzz::reply_to_sender,
+        Some(1),
+    );
+});
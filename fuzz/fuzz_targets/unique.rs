@@ -0,0 +1,17 @@
+#![no_main]
+#![allow(dead_code)]
+
+include!("../../src/bin/unique.rs");
+
+libfuzzer_sys::fuzz_target!(|data: &[u8]| {
+    maelstrom_fuzz::check::<InPayload, _, _>(
+        data,
+        |input, writer| maelstrom::run_node::<UniqueNode<_>, _, _, _>(input, writer),
+        |msg| match msg.body.payload {
+            // lin-kv replies are only expected when ids come from lin-kv
+            InPayload::ReadOk { .. } | InPayload::CasOk | InPayload::Error { .. } => None,
+            _ => maelstrom_fuzz::reply_to_sender(msg),
+        },
+        Some(1),
+    );
+});
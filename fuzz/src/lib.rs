@@ -0,0 +1,103 @@
+//! Shared harness for the `run_node` fuzz targets.
+//!
+//! Each target includes the source of one node binary, so the node type and its payloads are in
+//! scope, and hands them to [`check`]. The harness prepends a fixed `init` message, runs the node
+//! over the fuzzed lines and asserts that:
+//! - the node does not panic (errors for malformed input are fine),
+//! - every well-formed request gets exactly one reply, or at most one if the node bailed out
+//!   partway through the input,
+//! - nodes that answer each line with a single message never produce more output than that.
+//!
+//! Nothing here bounds the state a node keeps per message, so memory growth is only caught by
+//! libFuzzer's `-rss_limit_mb`/`-malloc_limit_mb`.
+
+use maelstrom::simulation::SharedWriter;
+use maelstrom::InMessage;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+pub const INIT: &str = r#"{"src":"c0","dest":"n0","body":{"type":"init","msg_id":1,"node_id":"n0","node_ids":["n0","n1","n2"]}}"#;
+
+#[derive(Deserialize)]
+struct Reply {
+    #[serde(rename = "dest")]
+    dst: String,
+    body: ReplyBody,
+}
+
+#[derive(Deserialize)]
+struct ReplyBody {
+    in_reply_to: Option<usize>,
+}
+
+/// The usual reply address: back to the sender, in reply to its `msg_id`.
+pub fn reply_to_sender<P>(msg: &InMessage<P>) -> Option<(String, usize)> {
+    msg.body.msg_id.map(|msg_id| (msg.src.clone(), msg_id))
+}
+
+/// Runs `run` over `data` and checks the node's output.
+///
+/// `reply_to` returns the `(dest, in_reply_to)` a message must be answered with, or `None` if
+/// it expects no reply. `fanout` bounds the number of messages the node may send per input line.
+pub fn check<P, R, F>(data: &[u8], run: R, reply_to: F, fanout: Option<usize>)
+where
+    P: DeserializeOwned,
+    R: FnOnce(&[u8], SharedWriter) -> anyhow::Result<()>,
+    F: Fn(&InMessage<P>) -> Option<(String, usize)>,
+{
+    let Ok(data) = std::str::from_utf8(data) else {
+        return;
+    };
+    let input = format!("{INIT}\n{data}");
+    let writer = SharedWriter::default();
+    let result = run(input.as_bytes(), writer.clone());
+
+    let mut expected: HashMap<(String, usize), usize> = HashMap::new();
+    expected.insert(("c0".to_string(), 1), 1);
+    let mut lines = 1;
+    for line in data.lines() {
+        let Ok(msg) = serde_json::from_str::<InMessage<P>>(line) else {
+            break;
+        };
+        lines += 1;
+        if let Some(key) = reply_to(&msg) {
+            *expected.entry(key).or_default() += 1;
+        }
+    }
+
+    let output = writer.take().expect("writer lock poisoned");
+    let output = std::str::from_utf8(&output).expect("node wrote invalid utf-8");
+    let mut actual: HashMap<(String, usize), usize> = HashMap::new();
+    let mut sent = 0;
+    for line in output.lines() {
+        let reply: Reply = serde_json::from_str(line).expect("node wrote a malformed message");
+        sent += 1;
+        if let Some(in_reply_to) = reply.body.in_reply_to {
+            *actual.entry((reply.dst, in_reply_to)).or_default() += 1;
+        }
+    }
+
+    if let Some(fanout) = fanout {
+        assert!(
+            sent <= lines * fanout,
+            "node sent {sent} messages for {lines} input lines"
+        );
+    }
+    for (key, count) in &actual {
+        let allowed = expected.get(key).copied().unwrap_or_default();
+        assert!(
+            *count <= allowed,
+            "{key:?} got {count} replies but only {allowed} requests"
+        );
+    }
+    if result.is_ok() {
+        for (key, count) in &expected {
+            let replies = actual.get(key).copied().unwrap_or_default();
+            assert_eq!(
+                *count, replies,
+                "{key:?} got {replies} replies to {count} requests"
+            );
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! How nodes are configured: the gossip mode, the overlay links run along and the order of reads.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use maelstrom::env_config;
+
+/// How nodes are configured, from the `BROADCAST_*` environment variables.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Config {
+    pub overlay: Overlay,
+    pub mode: Mode,
+    /// seeds the choice of peers in push-pull mode, mixed with the node id so nodes differ
+    pub seed: u64,
+    pub order: Order,
+}
+
+impl Config {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            overlay: env_config("BROADCAST_OVERLAY")?.unwrap_or_default(),
+            mode: env_config("BROADCAST_MODE")?.unwrap_or_default(),
+            seed: env_config("BROADCAST_SEED")?.unwrap_or_default(),
+            order: env_config("BROADCAST_ORDER")?.unwrap_or_default(),
+        })
+    }
+}
+
+/// How keys spread, set through the `BROADCAST_MODE` environment variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// `flood`: every key is pushed to every neighbor
+    #[default]
+    Flood,
+    /// `plumtree`: epidemic broadcast trees. Keys are pushed eagerly only along a spanning tree
+    /// and announced with `ihave` on the other links. A neighbor that pushes nothing new is
+    /// pruned from the tree, and one that announces a key that does not arrive on the tree in
+    /// time is grafted onto it.
+    Plumtree,
+    /// `push-pull[:<fanout>[:<round ms>]]`: infection-style gossip that ignores the overlay.
+    /// Every round the node picks `fanout` random nodes, pushes them the keys that are still
+    /// rumors along with the digests of all its keys, and pulls the difference through a
+    /// [`Replica::reconcile`](crate::replica::Replica::reconcile). Rumors age by one every round
+    /// and stop spreading once they are old enough to have reached every node.
+    PushPull { fanout: usize, round: Duration },
+}
+
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some("flood"), None, _, _) => Ok(Self::Flood),
+            (Some("plumtree"), None, _, _) => Ok(Self::Plumtree),
+            (Some("push-pull"), fanout, round, None) => {
+                let fanout = match fanout {
+                    Some(fanout) => fanout.parse().context("invalid fanout")?,
+                    None => Self::DEFAULT_FANOUT,
+                };
+                let round = match round {
+                    Some(round) => Duration::from_millis(round.parse().context("invalid round")?),
+                    None => Self::DEFAULT_ROUND,
+                };
+                if fanout == 0 || round.is_zero() {
+                    bail!("fanout and round must be at least 1");
+                }
+                Ok(Self::PushPull { fanout, round })
+            }
+            _ => bail!("expected flood, plumtree or push-pull[:<fanout>[:<round ms>]]"),
+        }
+    }
+}
+
+/// The links gossip travels along, set through the `BROADCAST_OVERLAY` environment variable.
+///
+/// Maelstrom's grid gives every node up to four neighbors and long paths, so most messages are
+/// duplicates. The trees only send each key once per link, and on 25 nodes with 100ms links
+/// `tree:4` stays within 3 hops of the root, which keeps the median latency well below 1s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overlay {
+    /// `maelstrom`: whatever the `topology` message says
+    #[default]
+    Maelstrom,
+    /// `hub`: every node talks to the first node only, two hops between any pair
+    Hub,
+    /// `tree:<fanout>`: a spanning tree over the sorted node ids with `fanout` children per node,
+    /// or `redundant-tree:<fanout>` for the union with the same tree over the reversed ids, so
+    /// that no single link or inner node partitions the others
+    Tree { fanout: usize, redundant: bool },
+}
+
+impl FromStr for Overlay {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, fanout) = match s.split_once(':') {
+            Some((name, fanout)) => (name, Some(fanout.parse().context("invalid fanout")?)),
+            None => (s, None),
+        };
+        match (name, fanout) {
+            ("maelstrom", None) => Ok(Self::Maelstrom),
+            ("hub", None) => Ok(Self::Hub),
+            (_, Some(0)) => bail!("fanout must be at least 1"),
+            ("tree", fanout) => Ok(Self::Tree {
+                fanout: fanout.unwrap_or(Self::DEFAULT_FANOUT),
+                redundant: false,
+            }),
+            ("redundant-tree", fanout) => Ok(Self::Tree {
+                fanout: fanout.unwrap_or(Self::DEFAULT_FANOUT),
+                redundant: true,
+            }),
+            _ => bail!("expected maelstrom, hub, tree[:<fanout>] or redundant-tree[:<fanout>]"),
+        }
+    }
+}
+
+/// What `read` guarantees about the keys it returns, set through the `BROADCAST_ORDER`
+/// environment variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Order {
+    /// `none`: keys are read as soon as they arrive
+    #[default]
+    Unordered,
+    /// `causal`: keys are read only after every key their origin had read when it broadcast them,
+    /// see [`Causal`](crate::order::Causal)
+    Causal,
+    /// `total`: keys are read in the same order on every node, see [`Total`](crate::order::Total)
+    Total,
+}
+
+impl FromStr for Order {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::Unordered),
+            "causal" => Ok(Self::Causal),
+            "total" => Ok(Self::Total),
+            _ => bail!("expected none, causal or total"),
+        }
+    }
+}
+
+impl Mode {
+    const DEFAULT_FANOUT: usize = 3;
+    const DEFAULT_ROUND: Duration = Duration::from_millis(100);
+}
+
+impl Overlay {
+    const DEFAULT_FANOUT: usize = 4;
+
+    /// the neighbors of `node_id`, or `None` if they come from the `topology` message
+    pub fn neighbors(self, node_id: &str, node_ids: &[String]) -> Option<Vec<String>> {
+        let mut sorted = node_ids.to_vec();
+        sorted.sort();
+        let position = |ids: &[String]| ids.iter().position(|id| id == node_id);
+        let mut neighbors = match self {
+            Self::Maelstrom => return None,
+            Self::Hub => match position(&sorted) {
+                Some(0) => sorted[1..].to_vec(),
+                Some(_) => sorted[..1].to_vec(),
+                None => Vec::new(),
+            },
+            Self::Tree { fanout, redundant } => {
+                let mut neighbors = tree_neighbors(&sorted, position(&sorted), fanout);
+                if redundant {
+                    sorted.reverse();
+                    neighbors.extend(tree_neighbors(&sorted, position(&sorted), fanout));
+                }
+                neighbors
+            }
+        };
+        neighbors.sort();
+        neighbors.dedup();
+        Some(neighbors)
+    }
+}
+
+fn tree_neighbors(node_ids: &[String], position: Option<usize>, fanout: usize) -> Vec<String> {
+    let Some(position) = position else {
+        return Vec::new();
+    };
+    let parent = position.checked_sub(1).map(|idx| idx / fanout);
+    let children = (position * fanout + 1)..(position * fanout + fanout + 1);
+    parent
+        .into_iter()
+        .chain(children)
+        .filter_map(|idx| node_ids.get(idx).cloned())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+
+    #[test]
+    fn overlays_are_connected_and_symmetric() {
+        let node_ids = (0..25).map(|idx| format!("n{idx}")).collect::<Vec<_>>();
+        for overlay in ["hub", "tree:1", "tree:4", "redundant-tree:3"] {
+            let overlay: Overlay = overlay.parse().unwrap();
+            let links = node_ids
+                .iter()
+                .map(|node_id| (node_id, overlay.neighbors(node_id, &node_ids).unwrap()))
+                .collect::<HashMap<_, _>>();
+            for (node_id, neighbors) in &links {
+                assert!(!neighbors.contains(node_id), "{overlay:?}");
+                for neighbor in neighbors {
+                    assert!(links[neighbor].contains(node_id), "{overlay:?}");
+                }
+            }
+            let mut reached = HashSet::from([&node_ids[0]]);
+            let mut frontier = vec![&node_ids[0]];
+            while let Some(node_id) = frontier.pop() {
+                for neighbor in &links[node_id] {
+                    if reached.insert(neighbor) {
+                        frontier.push(neighbor);
+                    }
+                }
+            }
+            assert_eq!(reached.len(), node_ids.len(), "{overlay:?}");
+        }
+    }
+}
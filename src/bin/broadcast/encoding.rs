@@ -0,0 +1,68 @@
+//! How key sets are written on the wire.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+/// Keys as they are sent between nodes. Unsigned integers, the keys of Maelstrom's workload, are
+/// sent as inclusive ranges, so `0` to `999` is `[[0, 999]]`, and other keys are sent as they are.
+#[derive(Serialize, Deserialize)]
+struct CompactKeys<V> {
+    ranges: Vec<(u64, u64)>,
+    values: V,
+}
+
+impl CompactKeys<()> {
+    /// bounds the keys a message may expand to, as ranges are cheap to send
+    const MAX_KEYS: u64 = 1 << 20;
+}
+
+pub fn serialize_keys<K, S>(keys: &K, serializer: S) -> Result<S::Ok, S::Error>
+where
+    K: AsRef<[Value]>,
+    S: Serializer,
+{
+    let (mut integers, values): (Vec<_>, Vec<_>) =
+        keys.as_ref().iter().partition(|key| key.is_u64());
+    integers.sort_by_key(|key| key.as_u64());
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for integer in integers.iter().filter_map(|key| key.as_u64()) {
+        match ranges.last_mut() {
+            Some((_, end)) if integer <= end.saturating_add(1) => *end = integer,
+            _ => ranges.push((integer, integer)),
+        }
+    }
+    CompactKeys { ranges, values }.serialize(serializer)
+}
+
+/// Expands [`CompactKeys`], or rejects them with `None` if the ranges are inverted or add up to
+/// more than [`CompactKeys::MAX_KEYS`], so that only the message is dropped and not the node.
+pub fn deserialize_keys<'de, D>(deserializer: D) -> Result<Option<Vec<Value>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let CompactKeys { ranges, mut values } = CompactKeys::<Vec<Value>>::deserialize(deserializer)?;
+    let mut count = values.len() as u64;
+    for &(start, end) in &ranges {
+        count = count.saturating_add(end.saturating_sub(start).saturating_add(1));
+        if start > end || count > CompactKeys::MAX_KEYS {
+            log::warn!("rejecting {count} keys in ranges {ranges:?}");
+            return Ok(None);
+        }
+    }
+    values.extend(
+        ranges
+            .into_iter()
+            .flat_map(|(start, end)| start..=end)
+            .map(Value::from),
+    );
+    Ok(Some(values))
+}
+
+/// the keys of a reconciliation, which still has its digests answered if its keys are rejected,
+/// as the next round sends the difference again
+pub fn deserialize_reconciled_keys<'de, D>(deserializer: D) -> Result<Vec<Value>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(deserialize_keys(deserializer)?.unwrap_or_default())
+}
@@ -0,0 +1,995 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context};
+use maelstrom::{
+    run_node, Body, DeconstructedInMessage, InMessage, MessageSerializer, Node, OutMessage,
+    PartialInMessage,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use config::{Config, Mode};
+use encoding::{deserialize_keys, serialize_keys};
+use order::Total;
+use reconcile::{Reconciliation, Rounds};
+use replica::{Outgoing, Replica, Report};
+
+mod config;
+mod encoding;
+mod order;
+mod reconcile;
+mod replica;
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum InPayload {
+    Broadcast {
+        message: Value,
+    },
+    Read,
+    Topology {
+        topology: HashMap<String, Vec<String>>,
+    },
+    Gossip {
+        /// `None` if the keys were rejected, see [`deserialize_keys`]
+        #[serde(deserialize_with = "deserialize_keys")]
+        messages: Option<Vec<Value>>,
+        end: usize,
+        #[serde(default)]
+        retransmit: bool,
+    },
+    GossipOk {
+        end: usize,
+    },
+    Reconcile(Reconciliation),
+    #[serde(rename = "ihave")]
+    IHave {
+        positions: Vec<u64>,
+        end: usize,
+    },
+    Graft {
+        positions: Vec<u64>,
+    },
+    Prune,
+    Stats,
+    Sequence {
+        message: Value,
+        epoch: u64,
+    },
+}
+
+#[derive(Copy, Clone, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum OutPayload<'a> {
+    BroadcastOk,
+    ReadOk {
+        messages: &'a [Value],
+    },
+    TopologyOk,
+    Gossip {
+        #[serde(serialize_with = "serialize_keys")]
+        messages: &'a [Value],
+        end: usize,
+        retransmit: bool,
+    },
+    GossipOk {
+        end: usize,
+    },
+    Reconcile(&'a Reconciliation),
+    #[serde(rename = "ihave")]
+    IHave {
+        positions: &'a [u64],
+        end: usize,
+    },
+    Graft {
+        positions: &'a [u64],
+    },
+    Prune,
+    StatsOk(&'a Report),
+    Sequence {
+        message: &'a Value,
+        epoch: u64,
+    },
+}
+
+struct BroadcastNode<W>
+where
+    W: std::io::Write + Send + Sync + 'static,
+{
+    node_id: String,
+    serializer: Arc<Mutex<MessageSerializer<W>>>,
+    replica: Arc<Mutex<Replica>>,
+    /// whether neighbors come from the `topology` message rather than the
+    /// [`Overlay`](config::Overlay)
+    follow_topology: bool,
+    handle: JoinHandle<anyhow::Result<()>>,
+    tx: Sender<bool>,
+}
+
+impl<W> Node<W, InPayload> for BroadcastNode<W>
+where
+    W: std::io::Write + Send + Sync,
+{
+    fn new(
+        node_id: String,
+        neighbors: Vec<String>,
+        serializer: MessageSerializer<W>,
+    ) -> anyhow::Result<Self> {
+        let config = Config::from_env()?;
+        Ok(Self::with_config(node_id, neighbors, serializer, config))
+    }
+
+    fn process(&mut self, in_msg: InMessage<InPayload>) -> anyhow::Result<()> {
+        let DeconstructedInMessage {
+            partial_in_msg,
+            in_payload,
+        } = in_msg.into();
+        match in_payload {
+            InPayload::Broadcast { message } => self.handle_broadcast_msg(partial_in_msg, message),
+            InPayload::Read => self.handle_read_msg(partial_in_msg),
+            InPayload::Topology { topology } => self.handle_topology_msg(partial_in_msg, topology),
+            InPayload::Gossip {
+                messages,
+                end,
+                retransmit,
+            } => match messages {
+                Some(messages) => self.handle_gossip_msg(partial_in_msg, messages, end, retransmit),
+                // not acked, so the sender keeps the keys
+                None => Ok(()),
+            },
+            InPayload::GossipOk { end } => self.handle_gossip_ok_msg(partial_in_msg, end),
+            InPayload::Reconcile(reconciliation) => {
+                self.handle_reconcile_msg(partial_in_msg, reconciliation)
+            }
+            InPayload::IHave { positions, end } => {
+                self.handle_ihave_msg(partial_in_msg, positions, end)
+            }
+            InPayload::Graft { positions } => self.handle_graft_msg(partial_in_msg, positions),
+            InPayload::Prune => {
+                self.lock_replica()?.prune(&partial_in_msg.src);
+                Ok(())
+            }
+            InPayload::Stats => self.handle_stats_msg(partial_in_msg),
+            InPayload::Sequence { message, epoch } => self.handle_sequence_msg(message, epoch),
+        }
+    }
+
+    fn shutdown(self) -> anyhow::Result<()> {
+        self.tx
+            .send(true)
+            .context("failed to send shutdown signal to gossip thread")?;
+        self.handle
+            .join()
+            .map_err(|_| anyhow!("failed to join gossip thread"))??;
+        let report = self
+            .replica
+            .lock()
+            .map_err(|_| anyhow!("failed to acquire lock for replica"))?
+            .stats
+            .report();
+        let report = serde_json::to_string(&report).context("failed to serialize stats")?;
+        eprintln!("{} stats: {report}", self.node_id);
+        Ok(())
+    }
+}
+
+impl<W> BroadcastNode<W>
+where
+    W: std::io::Write + Send + Sync,
+{
+    fn with_config(
+        node_id: String,
+        mut node_ids: Vec<String>,
+        serializer: MessageSerializer<W>,
+        config: Config,
+    ) -> Self {
+        let rounds = match config.mode {
+            Mode::PushPull { fanout, round } => Some(Rounds::new(
+                &node_id,
+                node_ids.clone(),
+                fanout,
+                round,
+                config.seed,
+            )),
+            _ => None,
+        };
+        node_ids.push(node_id.clone());
+        let mut replica = Replica::new(config.mode, config.order);
+        if let Some(total) = &mut replica.total {
+            total.join(&node_id, node_ids.clone());
+        }
+        let overlay_neighbors = match rounds {
+            Some(_) => Some(Vec::new()),
+            None => config.overlay.neighbors(&node_id, &node_ids),
+        };
+        let follow_topology = overlay_neighbors.is_none();
+        replica.set_neighbors(overlay_neighbors.unwrap_or_default());
+
+        let serializer = Arc::new(Mutex::new(serializer));
+        let replica = Arc::new(Mutex::new(replica));
+        let (tx, rx) = mpsc::channel();
+        let handle = {
+            let node_id = node_id.clone();
+            let replica = Arc::clone(&replica);
+            let serializer = Arc::clone(&serializer);
+            thread::spawn(move || replicate(node_id, replica, serializer, rounds, rx))
+        };
+        Self {
+            node_id,
+            serializer,
+            replica,
+            follow_topology,
+            handle,
+            tx,
+        }
+    }
+
+    fn handle_broadcast_msg(
+        &mut self,
+        partial_in_msg: PartialInMessage,
+        message: Value,
+    ) -> anyhow::Result<()> {
+        let forward = {
+            let mut guard = self.lock_replica()?;
+            let replica = &mut *guard;
+            replica.stats.broadcasts += 1;
+            let is_sequencer = replica.total.as_ref().is_some_and(Total::is_sequencer);
+            let key = match (&mut replica.causal, &mut replica.total) {
+                (Some(causal), _) => Some(causal.stamp(&self.node_id, message.clone())?),
+                (_, Some(total)) if is_sequencer => total.sequence(message.clone())?,
+                (_, Some(_)) => None,
+                (None, None) => Some(message.clone()),
+            };
+            if let Some(key) = key {
+                replica.insert(key, None);
+            }
+            let forward = match &mut replica.total {
+                Some(total) if !is_sequencer => total
+                    .forward(&message, Instant::now())
+                    .then(|| (total.sequencer().to_string(), total.epoch)),
+                _ => None,
+            };
+            replica.stats.sent += u64::from(forward.is_some());
+            forward
+        };
+        let mut out_msg = partial_in_msg.to_out_msg(OutPayload::BroadcastOk);
+        let mut serializer = self.lock_serializer()?;
+        serializer
+            .send(&mut out_msg)
+            .context("failed to serialize broadcast_ok message")?;
+        if let Some((sequencer, epoch)) = forward {
+            let payload = OutPayload::Sequence {
+                message: &message,
+                epoch,
+            };
+            let mut out_msg = OutMessage::new(&self.node_id, &sequencer, None, payload);
+            serializer
+                .send(&mut out_msg)
+                .context("failed to serialize sequence message")?;
+        }
+        Ok(())
+    }
+
+    /// The sequenced key reaches the forwarding node through replication, so there is no reply.
+    /// A forward for a later epoch makes this node its sequencer, and one this node is not the
+    /// sequencer for is forwarded on to the sequencer it knows of.
+    fn handle_sequence_msg(&mut self, message: Value, epoch: u64) -> anyhow::Result<()> {
+        let forward = {
+            let mut guard = self.lock_replica()?;
+            let replica = &mut *guard;
+            let Some(total) = &mut replica.total else {
+                return Ok(());
+            };
+            total.epoch = total.epoch.max(epoch);
+            if total.is_sequencer() {
+                if let Some(key) = total.sequence(message)? {
+                    replica.insert(key, None);
+                }
+                return Ok(());
+            }
+            let forward = total
+                .forward(&message, Instant::now())
+                .then(|| (total.sequencer().to_string(), total.epoch));
+            replica.stats.sent += u64::from(forward.is_some());
+            forward
+        };
+        if let Some((sequencer, epoch)) = forward {
+            let payload = OutPayload::Sequence {
+                message: &message,
+                epoch,
+            };
+            let mut out_msg = OutMessage::new(&self.node_id, &sequencer, None, payload);
+            self.lock_serializer()?
+                .send(&mut out_msg)
+                .context("failed to serialize sequence message")?;
+        }
+        Ok(())
+    }
+
+    fn handle_read_msg(&mut self, partial_in_msg: PartialInMessage) -> anyhow::Result<()> {
+        let messages = {
+            let replica = self.lock_replica()?;
+            match (&replica.causal, &replica.total) {
+                (Some(causal), _) => causal.delivered.clone(),
+                (_, Some(total)) => total.delivered.clone(),
+                (None, None) => replica.log.clone(),
+            }
+        };
+        let payload = OutPayload::ReadOk {
+            messages: messages.as_slice(),
+        };
+        let mut out_msg = partial_in_msg.to_out_msg(payload);
+        self.lock_serializer()?
+            .send(&mut out_msg)
+            .context("failed to serialize read_ok message")
+    }
+
+    /// The sender obviously has every message it gossips, so it is never sent them back. Only
+    /// gossip sent for the first time can tell a redundant link: a retransmission repeats keys
+    /// because an ack got lost, not because they came through another neighbor first.
+    fn handle_gossip_msg(
+        &mut self,
+        partial_in_msg: PartialInMessage,
+        messages: Vec<Value>,
+        end: usize,
+        retransmit: bool,
+    ) -> anyhow::Result<()> {
+        let prune = {
+            let mut replica = self.lock_replica()?;
+            let mut new = 0;
+            for message in messages {
+                new += usize::from(replica.insert(message, Some(&partial_in_msg.src)));
+            }
+            let prune = new == 0 && !retransmit && replica.received_duplicates(&partial_in_msg.src);
+            replica.stats.sent += 1 + u64::from(prune);
+            prune
+        };
+        let payload = OutPayload::GossipOk { end };
+        let mut out_msg = partial_in_msg.to_out_msg(payload);
+        let mut serializer = self.lock_serializer()?;
+        serializer
+            .send(&mut out_msg)
+            .context("failed to serialize gossip_ok message")?;
+        if prune {
+            let mut out_msg = OutMessage::new(
+                &partial_in_msg.dst,
+                &partial_in_msg.src,
+                None,
+                OutPayload::Prune,
+            );
+            serializer
+                .send(&mut out_msg)
+                .context("failed to serialize prune message")?;
+        }
+        Ok(())
+    }
+
+    /// announcements are acked like gossip, they only differ in what they carry
+    fn handle_ihave_msg(
+        &mut self,
+        partial_in_msg: PartialInMessage,
+        positions: Vec<u64>,
+        end: usize,
+    ) -> anyhow::Result<()> {
+        {
+            let mut replica = self.lock_replica()?;
+            replica.announce(&partial_in_msg.src, positions, Instant::now());
+            replica.stats.sent += 1;
+        }
+        let mut out_msg = partial_in_msg.to_out_msg(OutPayload::GossipOk { end });
+        self.lock_serializer()?
+            .send(&mut out_msg)
+            .context("failed to serialize gossip_ok message")
+    }
+
+    /// the grafted keys are sent like the keys of a reconciliation, which are not acked
+    fn handle_graft_msg(
+        &mut self,
+        partial_in_msg: PartialInMessage,
+        positions: Vec<u64>,
+    ) -> anyhow::Result<()> {
+        let messages = {
+            let mut replica = self.lock_replica()?;
+            replica.stats.sent += 1;
+            replica.graft(&partial_in_msg.src, positions)
+        };
+        let reconciliation = Reconciliation {
+            messages,
+            ..Default::default()
+        };
+        let payload = OutPayload::Reconcile(&reconciliation);
+        let mut out_msg = OutMessage::new(&partial_in_msg.dst, &partial_in_msg.src, None, payload);
+        self.lock_serializer()?
+            .send(&mut out_msg)
+            .context("failed to serialize reconcile message")
+    }
+
+    fn handle_topology_msg(
+        &mut self,
+        partial_in_msg: PartialInMessage,
+        mut topology: HashMap<String, Vec<String>>,
+    ) -> anyhow::Result<()> {
+        if self.follow_topology {
+            let neighbors = topology
+                .remove(&self.node_id)
+                .ok_or(anyhow!("topology does not contain self"))?;
+            self.lock_replica()?.set_neighbors(neighbors);
+        }
+
+        let mut out_msg = partial_in_msg.to_out_msg(OutPayload::TopologyOk);
+        self.lock_serializer()?
+            .send(&mut out_msg)
+            .context("failed to serialize topology_ok message")
+    }
+
+    fn handle_gossip_ok_msg(
+        &mut self,
+        partial_in_msg: PartialInMessage,
+        end: usize,
+    ) -> anyhow::Result<()> {
+        self.lock_replica()?
+            .ack(partial_in_msg.src, end, Instant::now());
+        Ok(())
+    }
+
+    /// replies are a continuation of the exchange rather than an answer, hence no `in_reply_to`
+    fn handle_reconcile_msg(
+        &mut self,
+        partial_in_msg: PartialInMessage,
+        reconciliation: Reconciliation,
+    ) -> anyhow::Result<()> {
+        let reply = {
+            let mut replica = self.lock_replica()?;
+            let reply = replica.reconcile(&partial_in_msg.src, reconciliation);
+            replica.stats.sent += u64::from(reply.is_some());
+            reply
+        };
+        if let Some(reply) = reply {
+            let payload = OutPayload::Reconcile(&reply);
+            let mut out_msg =
+                OutMessage::new(&partial_in_msg.dst, &partial_in_msg.src, None, payload);
+            self.lock_serializer()?
+                .send(&mut out_msg)
+                .context("failed to serialize reconcile message")?;
+        }
+        Ok(())
+    }
+
+    fn handle_stats_msg(&mut self, partial_in_msg: PartialInMessage) -> anyhow::Result<()> {
+        let report = self.lock_replica()?.stats.report();
+        let mut out_msg = partial_in_msg.to_out_msg(OutPayload::StatsOk(&report));
+        self.lock_serializer()?
+            .send(&mut out_msg)
+            .context("failed to serialize stats_ok message")
+    }
+
+    fn lock_replica(&self) -> anyhow::Result<MutexGuard<'_, Replica>> {
+        self.replica
+            .lock()
+            .map_err(|_| anyhow!("failed to acquire lock for replica"))
+    }
+
+    fn lock_serializer(&self) -> anyhow::Result<MutexGuard<'_, MessageSerializer<W>>> {
+        self.serializer
+            .lock()
+            .map_err(|_| anyhow!("failed to acquire lock for serializer"))
+    }
+}
+
+/// how long the gossip thread batches up new keys before sending them
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(50);
+
+/// how often the gossip thread checks whether anything is due
+const TICK: Duration = Duration::from_millis(5);
+
+/// how often each neighbor is sent digests to find keys it is missing nonetheless
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Runs on a separate thread and replicates all keys in other nodes by periodically gossiping.
+/// Every neighbor is sent a single message with all the keys it has not acked yet whenever
+/// [`Peer::due`](replica::Peer::due), and every [`RECONCILE_INTERVAL`] a [`Replica::reconcile`]
+/// is started to heal whatever else got lost. In push-pull mode there are no neighbors, and
+/// [`Rounds`] are sent instead. Messages are built first so the replica is not locked while
+/// sending.
+fn replicate<W>(
+    node_id: String,
+    replica: Arc<Mutex<Replica>>,
+    serializer: Arc<Mutex<MessageSerializer<W>>>,
+    mut rounds: Option<Rounds>,
+    rx: Receiver<bool>,
+) -> anyhow::Result<()>
+where
+    W: std::io::Write + Send + Sync,
+{
+    let mut last_reconcile = Instant::now();
+    while rx.try_recv().is_err() {
+        thread::sleep(TICK);
+        let now = Instant::now();
+        let (neighbors, digests, outgoing, grafts, round, forwards) = {
+            let mut replica = replica
+                .lock()
+                .map_err(|_| anyhow!("failed to acquire lock for replica"))?;
+            let digests = (now >= last_reconcile + RECONCILE_INTERVAL).then(|| replica.digests());
+            let neighbors = replica.peers.keys().cloned().collect::<Vec<_>>();
+            let outgoing = neighbors
+                .iter()
+                .filter_map(|neighbor| Some((neighbor.clone(), replica.outgoing(neighbor, now)?)))
+                .collect::<Vec<_>>();
+            let grafts = replica.overdue(now);
+            let round = rounds.as_mut().and_then(|rounds| {
+                let peers = rounds.due(now)?;
+                let mut push = replica.digests();
+                push.messages = replica.rumors(rounds.lifetime);
+                Some((peers, push))
+            });
+            let reconciles = digests.as_ref().map_or(0, |_| neighbors.len())
+                + round.as_ref().map_or(0, |(peers, _)| peers.len());
+            let forwards = replica.overdue_forwards(now)?;
+            let forwarded = forwards
+                .as_ref()
+                .map_or(0, |(_, _, messages)| messages.len());
+            replica.stats.sent += (reconciles + outgoing.len() + grafts.len() + forwarded) as u64;
+            (neighbors, digests, outgoing, grafts, round, forwards)
+        };
+        let mut serializer = serializer
+            .lock()
+            .map_err(|_| anyhow!("failed to acquire lock for serializer"))?;
+        if let Some(digests) = &digests {
+            last_reconcile = now;
+            for neighbor in &neighbors {
+                let mut out_msg =
+                    OutMessage::new(&node_id, neighbor, None, OutPayload::Reconcile(digests));
+                serializer
+                    .send(&mut out_msg)
+                    .context("failed to serialize reconcile message in gossip thread")?;
+            }
+        }
+        for (neighbor, (outgoing, end)) in outgoing {
+            let payload = match &outgoing {
+                Outgoing::Keys {
+                    messages,
+                    retransmit,
+                } => OutPayload::Gossip {
+                    messages,
+                    end,
+                    retransmit: *retransmit,
+                },
+                Outgoing::Positions(positions) => OutPayload::IHave { positions, end },
+            };
+            let mut out_msg = OutMessage {
+                src: &node_id,
+                dst: &neighbor,
+                body: Body {
+                    msg_id: None,
+                    in_reply_to: None,
+                    payload,
+                },
+            };
+            serializer
+                .send(&mut out_msg)
+                .context("failed to serialize gossip message in gossip thread")?;
+        }
+        if let Some((peers, push)) = &round {
+            for peer in peers {
+                let mut out_msg =
+                    OutMessage::new(&node_id, peer, None, OutPayload::Reconcile(push));
+                serializer
+                    .send(&mut out_msg)
+                    .context("failed to serialize push message in gossip thread")?;
+            }
+        }
+        if let Some((sequencer, epoch, messages)) = &forwards {
+            for message in messages {
+                let payload = OutPayload::Sequence {
+                    message,
+                    epoch: *epoch,
+                };
+                let mut out_msg = OutMessage::new(&node_id, sequencer, None, payload);
+                serializer
+                    .send(&mut out_msg)
+                    .context("failed to serialize sequence message in gossip thread")?;
+            }
+        }
+        for (neighbor, positions) in grafts {
+            let payload = OutPayload::Graft {
+                positions: &positions,
+            };
+            let mut out_msg = OutMessage::new(&node_id, &neighbor, None, payload);
+            serializer
+                .send(&mut out_msg)
+                .context("failed to serialize graft message in gossip thread")?;
+        }
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let reader = std::io::stdin().lock();
+    let writer = std::io::stdout();
+    run_node::<BroadcastNode<_>, _, _, _>(reader, writer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    use maelstrom::simulation::{step, Cluster, SharedWriter};
+    use proptest::prelude::*;
+    use proptest::sample::Index;
+    use serde_json::json;
+
+    use crate::config::Order;
+    use crate::reconcile::position;
+    use crate::replica::Peer;
+
+    type BroadcastCluster = Cluster<BroadcastNode<SharedWriter>, InPayload>;
+
+    fn cluster(node_count: usize, config: Config) -> BroadcastCluster {
+        BroadcastCluster::with_factory(node_count, move |node_id, node_ids, serializer| {
+            Ok(BroadcastNode::with_config(
+                node_id, node_ids, serializer, config,
+            ))
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn total_order_fails_over_to_the_next_sequencer() {
+        let config = Config {
+            order: Order::Total,
+            ..Default::default()
+        };
+        let mut cluster = cluster(3, config);
+        // a line from n1 over n2 to n0, so n1 and n2 stay connected without n0
+        set_line_topology(&mut cluster, 1).unwrap();
+        let read = |cluster: &mut BroadcastCluster, node_ids: &[&str]| {
+            cluster.take_replies()?;
+            for node_id in node_ids {
+                cluster.request("c0", node_id, json!({ "type": "read" }))?;
+            }
+            let replies = cluster.take_replies()?;
+            anyhow::Ok(
+                replies
+                    .iter()
+                    .map(|reply| reply["body"]["messages"].as_array().unwrap().clone())
+                    .collect::<Vec<_>>(),
+            )
+        };
+        for message in 0..3 {
+            let body = json!({ "type": "broadcast", "message": message });
+            cluster.request("c0", "n1", body).unwrap();
+        }
+        let mut before = Vec::new();
+        let delivered = cluster
+            .run_until(Duration::from_secs(5), |cluster| {
+                before = read(cluster, &["n0", "n1", "n2"])?;
+                Ok(before.iter().all(|messages| messages.len() == 3))
+            })
+            .unwrap();
+        assert!(delivered, "{before:?}");
+
+        cluster.drop_where(|msg| msg["src"] == "n0" || msg["dest"] == "n0");
+        for message in 3..9 {
+            let body = json!({ "type": "broadcast", "message": message });
+            cluster
+                .request("c0", &format!("n{}", 1 + message % 2), body)
+                .unwrap();
+        }
+        let mut reads = Vec::new();
+        let delivered = cluster
+            .run_until(Duration::from_secs(10), |cluster| {
+                reads = read(cluster, &["n1", "n2"])?;
+                Ok(reads.iter().all(|messages| messages.len() == 9))
+            })
+            .unwrap();
+        assert!(delivered, "{reads:?}");
+        assert_eq!(reads[0], reads[1]);
+        assert_eq!(reads[0][..3], before[0]);
+        cluster.shutdown().unwrap();
+    }
+
+    #[test]
+    fn total_order_keeps_values_numbered_on_both_sides_of_a_partition() {
+        let config = Config {
+            order: Order::Total,
+            ..Default::default()
+        };
+        let mut cluster = cluster(3, config);
+        let topology = json!({ "n0": ["n1", "n2"], "n1": ["n0", "n2"], "n2": ["n0", "n1"] });
+        for node_id in ["n0", "n1", "n2"] {
+            let body = json!({ "type": "topology", "topology": topology });
+            cluster.request("c0", node_id, body).unwrap();
+        }
+
+        // n1 fails over to its own epoch and numbers X, while n0 numbers Y under the same number
+        cluster.drop_where(|msg| msg["src"] == "n1" || msg["dest"] == "n1");
+        let x = json!({ "type": "broadcast", "message": "x" });
+        cluster.request("c0", "n1", x).unwrap();
+        let y = json!({ "type": "broadcast", "message": "y" });
+        cluster.request("c0", "n0", y).unwrap();
+        cluster.run_for(Duration::from_secs(4)).unwrap();
+
+        cluster.drop_where(|_| false);
+        let mut reads = Vec::new();
+        let delivered = cluster
+            .run_until(Duration::from_secs(10), |cluster| {
+                cluster.take_replies()?;
+                for node_id in ["n0", "n1", "n2"] {
+                    cluster.request("c0", node_id, json!({ "type": "read" }))?;
+                }
+                reads = cluster
+                    .take_replies()?
+                    .iter()
+                    .map(|reply| reply["body"]["messages"].as_array().unwrap().clone())
+                    .collect::<Vec<_>>();
+                Ok(reads.iter().all(|messages| messages.len() == 2))
+            })
+            .unwrap();
+        assert!(delivered, "{reads:?}");
+        // n0 and n2 read Y before X arrived, n1 read X first
+        assert_eq!(reads[0], [json!("y"), json!("x")]);
+        assert_eq!(reads[1], [json!("x"), json!("y")]);
+        assert_eq!(reads[2], reads[0]);
+        cluster.shutdown().unwrap();
+    }
+
+    #[test]
+    fn integer_keys_are_sent_as_ranges() {
+        let mut keys = (0..1000).map(|key| json!(key)).collect::<Vec<_>>();
+        keys.extend([json!(1007), json!(1005), json!(-1), json!("a"), json!(1006)]);
+        let payload = OutPayload::Gossip {
+            messages: &keys,
+            end: 1,
+            retransmit: false,
+        };
+        let serialized = serde_json::to_value(payload).unwrap();
+        assert_eq!(
+            serialized["messages"],
+            json!({ "ranges": [[0, 999], [1005, 1007]], "values": [-1, "a"] })
+        );
+
+        let InPayload::Gossip {
+            messages: Some(messages),
+            ..
+        } = serde_json::from_value(serialized).unwrap()
+        else {
+            panic!("expected gossip");
+        };
+        let mut expected = keys.iter().map(position).collect::<Vec<_>>();
+        let mut actual = messages.iter().map(position).collect::<Vec<_>>();
+        expected.sort();
+        actual.sort();
+        assert_eq!(actual, expected);
+
+        let huge = json!({ "type": "gossip", "messages": { "ranges": [[0, u64::MAX]], "values": [] }, "end": 1, "retransmit": false });
+        let mut cluster = cluster(1, Config::default());
+        cluster.request("n1", "n0", huge).unwrap();
+        assert!(cluster.take_replies().unwrap().is_empty());
+        cluster
+            .request("c0", "n0", json!({ "type": "read" }))
+            .unwrap();
+        let replies = cluster.take_replies().unwrap();
+        assert_eq!(replies[0]["body"]["type"], "read_ok");
+        cluster.shutdown().unwrap();
+    }
+
+    #[test]
+    fn plumtree_prunes_redundant_links() {
+        let gossiped = [Mode::Flood, Mode::Plumtree].map(|mode| {
+            let config = Config {
+                mode,
+                ..Default::default()
+            };
+            let mut cluster = cluster(5, config);
+            let node_ids = cluster.node_ids().map(String::from).collect::<Vec<_>>();
+            let topology = node_ids
+                .iter()
+                .map(|node_id| {
+                    (
+                        node_id,
+                        node_ids.iter().filter(|id| *id != node_id).collect(),
+                    )
+                })
+                .collect::<HashMap<_, Vec<_>>>();
+            for node_id in &node_ids {
+                let body = json!({ "type": "topology", "topology": topology });
+                cluster.request("c0", node_id, body).unwrap();
+            }
+            for message in 0..20 {
+                let body = json!({ "type": "broadcast", "message": message });
+                cluster.request("c0", &node_ids[0], body).unwrap();
+                cluster.run_for(Duration::from_millis(100)).unwrap();
+            }
+            let sent = (0..20).collect::<BTreeSet<_>>();
+            let converged = cluster
+                .run_until(Duration::from_secs(5), |cluster| {
+                    Ok(read_all(cluster)?.iter().all(|messages| messages == &sent))
+                })
+                .unwrap();
+            assert!(converged, "{mode:?} did not converge");
+            let gossiped = cluster.delivered("gossip");
+            cluster.shutdown().unwrap();
+            gossiped
+        });
+        assert!(
+            gossiped[1] * 2 < gossiped[0],
+            "plumtree sent {} gossip messages, flood {}",
+            gossiped[1],
+            gossiped[0]
+        );
+    }
+
+    #[test]
+    fn retransmissions_do_not_prune() {
+        let config = Config {
+            mode: Mode::Plumtree,
+            ..Default::default()
+        };
+        let mut cluster = cluster(2, config);
+        set_line_topology(&mut cluster, 0).unwrap();
+        cluster.drop_where(|msg| msg["body"]["type"] == "gossip_ok");
+        let body = json!({ "type": "broadcast", "message": 1 });
+        cluster.request("c0", "n0", body).unwrap();
+        cluster.run_for(Peer::INITIAL_RTO * 4).unwrap();
+        assert!(cluster.delivered("gossip") > 1);
+        assert_eq!(cluster.delivered("prune"), 0);
+        cluster.shutdown().unwrap();
+    }
+
+    #[test]
+    fn stats_cover_every_broadcast() {
+        let mut cluster = BroadcastCluster::new(3).unwrap();
+        let node_ids = cluster.node_ids().map(String::from).collect::<Vec<_>>();
+        set_line_topology(&mut cluster, 0).unwrap();
+        for message in 0..10 {
+            let body = json!({ "type": "broadcast", "message": message });
+            cluster.request("c0", &node_ids[message % 3], body).unwrap();
+        }
+        let covered = cluster
+            .run_until(Duration::from_secs(5), |cluster| {
+                cluster.take_replies()?;
+                for node_id in &node_ids {
+                    cluster.request("c0", node_id, json!({ "type": "stats" }))?;
+                }
+                Ok(cluster
+                    .take_replies()?
+                    .iter()
+                    .all(|reply| reply["body"]["covered"] == 10))
+            })
+            .unwrap();
+        assert!(covered, "not every broadcast was acked by all neighbors");
+
+        cluster.take_replies().unwrap();
+        for node_id in &node_ids {
+            cluster
+                .request("c0", node_id, json!({ "type": "stats" }))
+                .unwrap();
+        }
+        let reports = cluster.take_replies().unwrap();
+        let broadcasts = reports
+            .iter()
+            .map(|reply| reply["body"]["broadcasts"].as_u64().unwrap())
+            .sum::<u64>();
+        assert_eq!(broadcasts, 10);
+        cluster.shutdown().unwrap();
+    }
+
+    #[derive(Debug, Clone)]
+    enum Action {
+        Broadcast {
+            node: Index,
+            message: usize,
+        },
+        Topology(Index),
+        /// sends the last topology again
+        RepeatTopology,
+    }
+
+    fn action() -> impl Strategy<Value = Action> {
+        prop_oneof![
+            4 => (any::<Index>(), 0..1000usize)
+                .prop_map(|(node, message)| Action::Broadcast { node, message }),
+            1 => any::<Index>().prop_map(Action::Topology),
+            1 => Just(Action::RepeatTopology),
+        ]
+    }
+
+    /// sends every node the topology of a line through the nodes, starting at `start`
+    fn set_line_topology(cluster: &mut BroadcastCluster, start: usize) -> anyhow::Result<()> {
+        let mut node_ids = cluster.node_ids().map(String::from).collect::<Vec<_>>();
+        node_ids.rotate_left(start);
+        let topology = node_ids
+            .iter()
+            .enumerate()
+            .map(|(idx, node_id)| {
+                let line = node_ids[idx.saturating_sub(1)..(idx + 2).min(node_ids.len())]
+                    .iter()
+                    .filter(|id| *id != node_id)
+                    .collect::<Vec<_>>();
+                (node_id, line)
+            })
+            .collect::<HashMap<_, _>>();
+        for node_id in &node_ids {
+            let body = json!({ "type": "topology", "topology": topology });
+            cluster.request("c0", node_id, body)?;
+        }
+        Ok(())
+    }
+
+    fn read_all(cluster: &mut BroadcastCluster) -> anyhow::Result<Vec<BTreeSet<usize>>> {
+        cluster.take_replies()?;
+        let node_ids = cluster.node_ids().map(String::from).collect::<Vec<_>>();
+        for node_id in &node_ids {
+            cluster.request("c0", node_id, json!({ "type": "read" }))?;
+        }
+        Ok(cluster
+            .take_replies()?
+            .iter()
+            .map(|reply| serde_json::from_value(reply["body"]["messages"].clone()).unwrap())
+            .collect())
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+        #[test]
+        fn broadcast_converges(
+            node_count in 1..5usize,
+            mode in prop_oneof![
+                Just(Mode::Flood),
+                Just(Mode::Plumtree),
+                Just(Mode::PushPull { fanout: 1, round: Duration::from_millis(20) }),
+            ],
+            order in prop_oneof![Just(Order::Unordered), Just(Order::Causal), Just(Order::Total)],
+            steps in proptest::collection::vec(step(action()), 0..40),
+        ) {
+            let mut cluster = cluster(node_count, Config { mode, order, ..Default::default() });
+            let node_ids = cluster.node_ids().map(String::from).collect::<Vec<_>>();
+            set_line_topology(&mut cluster, 0).unwrap();
+
+            let mut sent = BTreeSet::new();
+            let mut topology = 0;
+            for step in steps {
+                match cluster.apply(step).unwrap() {
+                    Some(Action::Broadcast { node, message }) => {
+                        let body = json!({ "type": "broadcast", "message": message });
+                        cluster.request("c0", &node_ids[node.index(node_count)], body).unwrap();
+                        sent.insert(message);
+                    }
+                    Some(Action::Topology(start)) => {
+                        topology = start.index(node_count);
+                        set_line_topology(&mut cluster, topology).unwrap()
+                    }
+                    Some(Action::RepeatTopology) => {
+                        set_line_topology(&mut cluster, topology).unwrap()
+                    }
+                    None => (),
+                }
+            }
+
+            let converged = cluster
+                .run_until(Duration::from_secs(5), |cluster| {
+                    Ok(read_all(cluster)?.iter().all(|messages| messages == &sent))
+                })
+                .unwrap();
+            prop_assert!(converged, "nodes did not converge to {sent:?}");
+
+            // once everything is acked, gossip stops until there is something new
+            let quiescent = cluster
+                .run_until(Duration::from_secs(10), |cluster| {
+                    let gossiped = cluster.delivered("gossip") + cluster.delivered("ihave");
+                    cluster.run_for(Duration::from_millis(100))?;
+                    Ok(cluster.delivered("gossip") + cluster.delivered("ihave") == gossiped)
+                })
+                .unwrap();
+            prop_assert!(quiescent, "gossip did not stop after converging");
+            cluster.shutdown().unwrap();
+        }
+    }
+}
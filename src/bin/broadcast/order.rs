@@ -0,0 +1,355 @@
+//! Delivery of keys in causal or total order, for the `BROADCAST_ORDER` setting.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::reconcile::position;
+
+/// A key as it is replicated in causal order: the broadcast value with its origin and the vector
+/// clock of the origin's deliveries, which counts its own broadcast as well.
+#[derive(Serialize, Deserialize)]
+struct Stamped {
+    message: Value,
+    origin: String,
+    clock: BTreeMap<String, u64>,
+}
+
+/// Delivers [`Stamped`] keys in causal order. A key is delivered once every key its origin had
+/// delivered before broadcasting it was delivered here too, and keys that arrive early wait.
+#[derive(Default)]
+pub struct Causal {
+    /// how many keys of each origin were delivered
+    clock: BTreeMap<String, u64>,
+    /// the broadcast values in delivery order, without duplicates
+    pub delivered: Vec<Value>,
+    seen: HashSet<u64>,
+    /// keys that arrived early, by the origin and count of the key each waits for
+    waiting: HashMap<(String, u64), Vec<Stamped>>,
+}
+
+impl Causal {
+    /// stamps a value broadcast by `node_id`, which can be delivered right away
+    pub fn stamp(&self, node_id: &str, message: Value) -> anyhow::Result<Value> {
+        let mut clock = self.clock.clone();
+        *clock.entry(node_id.to_string()).or_default() += 1;
+        let stamped = Stamped {
+            message,
+            origin: node_id.to_string(),
+            clock,
+        };
+        serde_json::to_value(stamped).context("failed to stamp message")
+    }
+
+    /// delivers `key` and every waiting key that depended on it, or makes it wait
+    pub fn receive(&mut self, key: &Value) {
+        let Ok(stamped) = Stamped::deserialize(key) else {
+            return;
+        };
+        let mut ready = vec![stamped];
+        while let Some(stamped) = ready.pop() {
+            let count = stamped
+                .clock
+                .get(&stamped.origin)
+                .copied()
+                .unwrap_or_default();
+            if count <= self.delivered_from(&stamped.origin) {
+                // delivered already
+                continue;
+            }
+            match self.awaits(&stamped) {
+                Some(missing) => self.waiting.entry(missing).or_default().push(stamped),
+                None => {
+                    let dot = (stamped.origin.clone(), count);
+                    self.deliver(stamped);
+                    ready.extend(self.waiting.remove(&dot).unwrap_or_default());
+                }
+            }
+        }
+    }
+
+    /// how many keys of `node_id` were delivered
+    fn delivered_from(&self, node_id: &str) -> u64 {
+        self.clock.get(node_id).copied().unwrap_or_default()
+    }
+
+    /// The origin and count of the next key missing before `stamped` can be delivered: the
+    /// previous key of its origin, or a key of another origin it depends on. `None` if ready.
+    fn awaits(&self, stamped: &Stamped) -> Option<(String, u64)> {
+        stamped.clock.iter().find_map(|(node_id, &count)| {
+            let next = self.delivered_from(node_id) + 1;
+            let needed = match *node_id == stamped.origin {
+                true => count - 1,
+                false => count,
+            };
+            (needed >= next).then(|| (node_id.clone(), next))
+        })
+    }
+
+    fn deliver(&mut self, stamped: Stamped) {
+        let count = stamped
+            .clock
+            .get(&stamped.origin)
+            .copied()
+            .unwrap_or_default();
+        self.clock.insert(stamped.origin, count);
+        if self.seen.insert(position(&stamped.message)) {
+            self.delivered.push(stamped.message);
+        }
+    }
+}
+
+/// A key as it is replicated in total order: the broadcast value with the sequence number the
+/// sequencer of `epoch` assigned it.
+#[derive(Serialize, Deserialize)]
+struct Sequenced {
+    message: Value,
+    epoch: u64,
+    seq: u64,
+}
+
+/// Delivers [`Sequenced`] keys in the order of their sequence numbers, which makes reads agree
+/// on the order of values across nodes.
+///
+/// Nodes take turns as sequencer by epoch, starting with the smallest id. Other nodes forward
+/// it their broadcasts until the sequenced key is replicated back to them, and the sequencer
+/// assigns every value a number only once. Once a forwarded value went unsequenced for
+/// [`Total::FAILOVER_TIMEOUT`], the forwarding node moves on to the next epoch and forwards to
+/// its sequencer instead, which takes over and continues after the highest sequence number it
+/// saw. Nodes follow the latest epoch they saw in keys or forwards.
+///
+/// Sequencers of different epochs may give the same number to different values, when they could
+/// not reach each other. The number then belongs to the key of the latest epoch, and the values
+/// of the other keys are numbered anew, so every value is still read everywhere. Nodes that had
+/// read a value under a number it lost read the winner of the number after it, so they disagree
+/// with the others on the order of those values; values numbered later are read in the same
+/// order everywhere.
+#[derive(Default)]
+pub struct Total {
+    node_id: String,
+    /// every node by id, the sequencer of an epoch being the one at the epoch modulo their number
+    members: Vec<String>,
+    /// the latest epoch seen
+    pub epoch: u64,
+    /// the next sequence number to assign, on the sequencer
+    next: u64,
+    /// the positions of values that were assigned a sequence number
+    sequenced: HashSet<u64>,
+    /// values forwarded to the sequencer that have not been replicated back yet, by position
+    forwarded: HashMap<u64, Forward>,
+    /// the broadcast values in sequence order, up to the first sequence number missing
+    pub delivered: Vec<Value>,
+    /// the positions of delivered values, which are read once even if numbered in two epochs
+    seen: HashSet<u64>,
+    /// the epoch and position of the key each delivered sequence number belongs to, by number
+    ranks: Vec<(u64, u64)>,
+    /// keys past the delivered numbers, with the epoch they were numbered in
+    waiting: BTreeMap<u64, (u64, Value)>,
+}
+
+struct Forward {
+    message: Value,
+    /// the epoch of the sequencer the value was forwarded to, and since when
+    epoch: u64,
+    since: Instant,
+    /// when to forward the value again
+    deadline: Instant,
+}
+
+impl Total {
+    const FORWARD_TIMEOUT: Duration = Duration::from_secs(1);
+    /// how long a forwarded value may go unsequenced before the next node takes over
+    const FAILOVER_TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// makes this node `node_id` among `node_ids`, which take turns as sequencer by id
+    pub fn join(&mut self, node_id: &str, mut node_ids: Vec<String>) {
+        node_ids.sort();
+        self.node_id = node_id.to_string();
+        self.members = node_ids;
+    }
+
+    /// the sequencer of the latest epoch seen
+    pub fn sequencer(&self) -> &str {
+        match self.members.len() {
+            0 => &self.node_id,
+            len => &self.members[(self.epoch % len as u64) as usize],
+        }
+    }
+
+    pub fn is_sequencer(&self) -> bool {
+        self.sequencer() == self.node_id
+    }
+
+    /// sequences a value on the sequencer, unless it was already
+    pub fn sequence(&mut self, message: Value) -> anyhow::Result<Option<Value>> {
+        if self.sequenced.contains(&position(&message)) {
+            return Ok(None);
+        }
+        let sequenced = Sequenced {
+            message,
+            epoch: self.epoch,
+            seq: self.next,
+        };
+        self.next += 1;
+        let key = serde_json::to_value(sequenced).context("failed to sequence message")?;
+        Ok(Some(key))
+    }
+
+    /// whether a value broadcast on another node needs to be forwarded to the sequencer
+    pub fn forward(&mut self, message: &Value, now: Instant) -> bool {
+        let position = position(message);
+        if self.sequenced.contains(&position) || self.forwarded.contains_key(&position) {
+            return false;
+        }
+        let forward = Forward {
+            message: message.clone(),
+            epoch: self.epoch,
+            since: now,
+            deadline: now + Self::FORWARD_TIMEOUT,
+        };
+        self.forwarded.insert(position, forward);
+        true
+    }
+
+    /// The forwarded values to forward again, to the sequencer of the latest epoch. If one went
+    /// unsequenced for too long, the next epoch begins, and if that makes this node the sequencer
+    /// these are all the values it forwarded, to sequence here.
+    pub fn overdue(&mut self, now: Instant) -> Vec<Value> {
+        let epoch = self.epoch;
+        if self
+            .forwarded
+            .values()
+            .any(|forward| forward.epoch == epoch && now >= forward.since + Self::FAILOVER_TIMEOUT)
+        {
+            self.epoch += 1;
+        }
+        let (epoch, is_sequencer) = (self.epoch, self.is_sequencer());
+        self.forwarded
+            .values_mut()
+            .filter(|forward| is_sequencer || forward.epoch != epoch || forward.deadline <= now)
+            .map(|forward| {
+                if forward.epoch != epoch {
+                    forward.epoch = epoch;
+                    forward.since = now;
+                }
+                forward.deadline = now + Self::FORWARD_TIMEOUT;
+                forward.message.clone()
+            })
+            .collect()
+    }
+
+    /// Delivers `key` and the waiting keys that follow it, or makes it wait. A sequence number
+    /// belongs to the key of the latest epoch, and of the highest position among those, so nodes
+    /// agree on it once they saw the same keys. A key that wins a number already delivered is
+    /// delivered right away.
+    pub fn receive(&mut self, key: &Value) {
+        let Ok(Sequenced {
+            message,
+            epoch,
+            seq,
+        }) = Sequenced::deserialize(key)
+        else {
+            return;
+        };
+        self.epoch = self.epoch.max(epoch);
+        self.next = self.next.max(seq + 1);
+        let rank = (epoch, position(&message));
+        let holder = match self.ranks.get(seq as usize) {
+            Some(holder) => Some(*holder),
+            None => self
+                .waiting
+                .get(&seq)
+                .map(|(epoch, message)| (*epoch, position(message))),
+        };
+        match holder {
+            Some(holder) if holder == rank => return,
+            Some(holder) if holder > rank => return self.lose(message),
+            _ => (),
+        }
+        self.sequenced.insert(rank.1);
+        self.forwarded.remove(&rank.1);
+        if let Some(holder) = self.ranks.get_mut(seq as usize) {
+            // the value delivered under the number was delivered, so it only loses the number
+            self.sequenced.remove(&holder.1);
+            *holder = rank;
+            self.deliver(message);
+        } else if let Some((_, loser)) = self.waiting.insert(seq, (epoch, message)) {
+            self.lose(loser);
+        }
+        while let Some((epoch, message)) = self.waiting.remove(&(self.ranks.len() as u64)) {
+            self.ranks.push((epoch, position(&message)));
+            self.deliver(message);
+        }
+    }
+
+    fn deliver(&mut self, message: Value) {
+        if self.seen.insert(position(&message)) {
+            self.delivered.push(message);
+        }
+    }
+
+    /// Forgets that a value has a sequence number after its key lost the number to another,
+    /// and forwards it to the sequencer to be numbered anew, unless it was delivered already.
+    fn lose(&mut self, message: Value) {
+        let position = position(&message);
+        self.sequenced.remove(&position);
+        if !self.seen.contains(&position) {
+            self.forward(&message, Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use crate::config::{Mode, Order};
+    use crate::replica::Replica;
+
+    #[test]
+    fn causal_reads_wait_for_predecessors() {
+        let mut n0 = Replica::new(Mode::Flood, Order::Causal);
+        let mut n1 = Replica::new(Mode::Flood, Order::Causal);
+        let first = n0.causal.as_ref().unwrap().stamp("n0", json!("a")).unwrap();
+        n0.insert(first.clone(), None);
+        let second = n0.causal.as_ref().unwrap().stamp("n0", json!("b")).unwrap();
+        n0.insert(second.clone(), None);
+        n1.insert(first.clone(), Some("n0"));
+        let third = n1.causal.as_ref().unwrap().stamp("n1", json!("c")).unwrap();
+
+        let mut n2 = Replica::new(Mode::Flood, Order::Causal);
+        n2.insert(third, Some("n1"));
+        n2.insert(second, Some("n0"));
+        assert!(n2.causal.as_ref().unwrap().delivered.is_empty());
+        n2.insert(first, Some("n0"));
+        let causal = n2.causal.as_ref().unwrap();
+        assert_eq!(causal.delivered[0], json!("a"));
+        assert_eq!(causal.delivered.len(), 3);
+        assert!(causal.waiting.is_empty());
+    }
+
+    #[test]
+    fn total_order_is_the_same_everywhere() {
+        let mut sequencer = Replica::new(Mode::Flood, Order::Total);
+        let mut keys = Vec::new();
+        for message in ["a", "b", "a", "c"] {
+            let key = sequencer.total.as_mut().unwrap().sequence(json!(message));
+            if let Some(key) = key.unwrap() {
+                sequencer.insert(key.clone(), None);
+                keys.push(key);
+            }
+        }
+        assert_eq!(keys.len(), 3);
+
+        let mut n1 = Replica::new(Mode::Flood, Order::Total);
+        for key in keys.iter().rev() {
+            n1.insert(key.clone(), Some("n0"));
+        }
+        let expected = [json!("a"), json!("b"), json!("c")];
+        assert_eq!(sequencer.total.unwrap().delivered, expected);
+        assert_eq!(n1.total.unwrap().delivered, expected);
+    }
+}
@@ -0,0 +1,225 @@
+//! Anti-entropy between key sets: digests over ranges of key positions, which find the keys
+//! gossip lost, and the rounds of push-pull mode, which spreads keys by reconciling only.
+
+use std::time::{Duration, Instant};
+
+use maelstrom::mix;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::encoding::{deserialize_reconciled_keys, serialize_keys};
+use crate::replica::Replica;
+
+/// One round of comparing key sets with a neighbor, see [`Replica::reconcile`].
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Reconciliation {
+    pub digests: Vec<RangeDigest>,
+    /// keys in ranges the other side asked for or will be asked for
+    #[serde(serialize_with = "serialize_keys")]
+    #[serde(deserialize_with = "deserialize_reconciled_keys")]
+    pub messages: Vec<Value>,
+    /// ranges the other side should answer with all its keys
+    pub wanted: Vec<(u64, u64)>,
+}
+
+impl Reconciliation {
+    fn is_empty(&self) -> bool {
+        self.digests.is_empty() && self.messages.is_empty() && self.wanted.is_empty()
+    }
+}
+
+/// Summarizes the keys whose [`position`] falls into `start..=end`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RangeDigest {
+    start: u64,
+    end: u64,
+    count: usize,
+    fingerprint: u64,
+}
+
+impl Replica {
+    const DIGEST_FANOUT: u64 = 16;
+    /// ranges with at most this many keys on either side are exchanged instead of split
+    const LEAF_SIZE: usize = 8;
+
+    /// digests of the whole position space, which start a reconciliation
+    pub fn digests(&self) -> Reconciliation {
+        Reconciliation {
+            digests: split(0, MAX_POSITION)
+                .map(|(start, end)| self.digest(start, end))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn digest(&self, start: u64, end: u64) -> RangeDigest {
+        let (count, fingerprint) = self
+            .index
+            .range(start..=end)
+            .fold((0, 0), |(count, fingerprint), (&position, _)| {
+                (count + 1, fingerprint ^ mix(position))
+            });
+        RangeDigest {
+            start,
+            end,
+            count,
+            fingerprint,
+        }
+    }
+
+    fn keys_in(&self, start: u64, end: u64) -> impl Iterator<Item = Value> + '_ {
+        self.index
+            .range(start..=end)
+            .map(|(_, &idx)| self.log[idx].clone())
+    }
+
+    /// Takes the keys `peer` sent and answers its digests: matching ranges are in sync, small
+    /// mismatching ones are exchanged in full and large ones split into finer digests for another
+    /// round. Traffic grows with the difference between the sets rather than their size.
+    pub fn reconcile(&mut self, peer: &str, theirs: Reconciliation) -> Option<Reconciliation> {
+        for message in theirs.messages {
+            self.insert(message, Some(peer));
+        }
+        let mut reply = Reconciliation::default();
+        for (start, end) in theirs.wanted {
+            reply.messages.extend(self.keys_in(start, end));
+        }
+        for digest in theirs.digests {
+            let ours = self.digest(digest.start, digest.end);
+            if (ours.count, ours.fingerprint) == (digest.count, digest.fingerprint) {
+                continue;
+            }
+            if ours.count <= Self::LEAF_SIZE || digest.count <= Self::LEAF_SIZE {
+                reply
+                    .messages
+                    .extend(self.keys_in(digest.start, digest.end));
+                if digest.count > 0 {
+                    reply.wanted.push((digest.start, digest.end));
+                }
+            } else {
+                let digests =
+                    split(digest.start, digest.end).map(|(start, end)| self.digest(start, end));
+                reply.digests.extend(digests);
+            }
+        }
+        (!reply.is_empty()).then_some(reply)
+    }
+}
+
+/// The schedule of push-pull gossip rounds and the peers to contact in each.
+pub struct Rounds {
+    /// every other node
+    members: Vec<String>,
+    fanout: usize,
+    interval: Duration,
+    /// how many rounds a rumor is pushed for, enough to reach `members` with a margin
+    pub lifetime: u32,
+    rng: StdRng,
+    last: Instant,
+}
+
+impl Rounds {
+    pub const MARGIN: u32 = 2;
+
+    pub fn new(
+        node_id: &str,
+        members: Vec<String>,
+        fanout: usize,
+        interval: Duration,
+        seed: u64,
+    ) -> Self {
+        let mut lifetime = Self::MARGIN;
+        let mut reached = 1;
+        while reached <= members.len() {
+            reached *= fanout + 1;
+            lifetime += 1;
+        }
+        let seed = seed ^ position(&Value::from(node_id));
+        Self {
+            members,
+            fanout,
+            interval,
+            lifetime,
+            rng: StdRng::seed_from_u64(seed),
+            last: Instant::now(),
+        }
+    }
+
+    /// the peers to contact if a round is due
+    pub fn due(&mut self, now: Instant) -> Option<Vec<String>> {
+        if now < self.last + self.interval {
+            return None;
+        }
+        self.last = now;
+        let peers = self.members.choose_multiple(&mut self.rng, self.fanout);
+        Some(peers.cloned().collect())
+    }
+}
+
+/// positions are kept below 2^63 so they survive JSON parsers that only handle signed integers
+const MAX_POSITION: u64 = i64::MAX as u64;
+
+/// Where a key lands in the space digests are computed over, from the FNV-1a hash of its
+/// canonical serialization; objects serialize with sorted keys. Distinct keys whose hashes
+/// collide would be treated as one, which is unlikely enough with 63 bits.
+pub fn position(message: &Value) -> u64 {
+    let hash = message
+        .to_string()
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325, |hash: u64, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+    mix(hash) >> 1
+}
+
+/// splits `start..=end` into up to [`Replica::DIGEST_FANOUT`] consecutive ranges
+fn split(start: u64, end: u64) -> impl Iterator<Item = (u64, u64)> {
+    let parts = Replica::DIGEST_FANOUT as u128;
+    let width = (end as u128 - start as u128 + 1).div_ceil(parts);
+    (0..parts)
+        .map(move |idx| start as u128 + idx * width)
+        .take_while(move |&first| first <= end as u128)
+        .map(move |first| (first as u64, (first + width - 1).min(end as u128) as u64))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn reconciliation_transfers_the_difference() {
+        let mut left = Replica::default();
+        let mut right = Replica::default();
+        for message in 0..10_000 {
+            left.insert(json!(message), None);
+            if message % 2000 != 0 {
+                right.insert(json!(message), None);
+            }
+        }
+        for message in ["a", "b", "c"] {
+            right.insert(json!(message), None);
+        }
+
+        let mut transferred = 0;
+        let mut next = Some(left.digests());
+        let mut sides = [("left", &mut left), ("right", &mut right)];
+        for round in 0.. {
+            let Some(reconciliation) = next else {
+                break;
+            };
+            transferred += reconciliation.messages.len();
+            let (from, _) = sides[round % 2];
+            let (_, to) = &mut sides[(round + 1) % 2];
+            next = to.reconcile(from, reconciliation);
+        }
+
+        assert!(left.index.keys().eq(right.index.keys()));
+        assert_eq!(left.index.len(), 10_003);
+        assert!(transferred < 200, "transferred {transferred} keys");
+    }
+}
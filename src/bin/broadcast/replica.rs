@@ -0,0 +1,633 @@
+//! The keys a node knows and the gossip state of each of its neighbors.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::{Mode, Order};
+use crate::order::{Causal, Total};
+use crate::reconcile::position;
+use crate::GOSSIP_INTERVAL;
+
+/// How efficiently keys spread, as seen by one node.
+#[derive(Default)]
+pub struct Stats {
+    /// client `broadcast` requests
+    pub broadcasts: u64,
+    /// messages sent to other nodes, of any type
+    pub sent: u64,
+    /// keys other nodes sent that were known already
+    duplicates: u64,
+    /// keys every neighbor acked, and how long after their receipt the last one did; keys
+    /// received while there were no neighbors are not counted
+    covered: u64,
+    coverage_total: Duration,
+    coverage_max: Duration,
+}
+
+impl Stats {
+    fn cover(&mut self, elapsed: Duration) {
+        self.covered += 1;
+        self.coverage_total += elapsed;
+        self.coverage_max = self.coverage_max.max(elapsed);
+    }
+
+    pub fn report(&self) -> Report {
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        Report {
+            broadcasts: self.broadcasts,
+            messages: self.sent,
+            msgs_per_op: self.sent as f64 / self.broadcasts.max(1) as f64,
+            duplicates: self.duplicates,
+            covered: self.covered,
+            mean_coverage_ms: ms(self.coverage_total) / self.covered.max(1) as f64,
+            max_coverage_ms: ms(self.coverage_max),
+        }
+    }
+}
+
+/// [`Stats`] as answered to `stats` requests and written to stderr on shutdown
+#[derive(Debug, Serialize)]
+pub struct Report {
+    broadcasts: u64,
+    messages: u64,
+    msgs_per_op: f64,
+    duplicates: u64,
+    covered: u64,
+    mean_coverage_ms: f64,
+    max_coverage_ms: f64,
+}
+
+/// Every key the node knows in the order it learned them, and how far into that order each
+/// neighbor confirmed receiving them, so gossip only needs to carry the keys past that mark.
+///
+/// Keys are arbitrary JSON values, told apart by the hash of their canonical serialization. Once
+/// every neighbor acked a key it is settled, and the log keeps nothing but the key itself.
+#[derive(Default)]
+pub struct Replica {
+    mode: Mode,
+    pub log: Vec<Value>,
+    /// what gossip needs to know about the keys past `covered`, which are not settled yet
+    unsettled: VecDeque<Entry>,
+    pub peers: HashMap<String, Peer>,
+    /// log indices by the [`position`] of their key, for deduplication and for digests over
+    /// ranges of positions
+    pub index: BTreeMap<u64, usize>,
+    /// keys announced by lazy peers that have not arrived yet, by position
+    announced: HashMap<u64, Announcement>,
+    /// the length of the log prefix every neighbor acked
+    covered: usize,
+    /// keys still spreading in push-pull mode, by position, with the rounds they were pushed in
+    rumors: HashMap<u64, u32>,
+    /// the causal delivery of keys if reads are ordered causally
+    pub causal: Option<Causal>,
+    /// the sequenced delivery of keys if reads are totally ordered
+    pub total: Option<Total>,
+    pub stats: Stats,
+}
+
+struct Announcement {
+    from: String,
+    /// when the key is grafted if it has not arrived by then
+    deadline: Instant,
+}
+
+/// gossip that is due for a neighbor
+pub enum Outgoing {
+    /// the keys themselves, for eager peers, and whether they were sent before
+    Keys {
+        messages: Vec<Value>,
+        retransmit: bool,
+    },
+    /// only their positions, for lazy peers
+    Positions(Vec<u64>),
+}
+
+/// A neighbor's progress through the log and the timers for retransmitting gossip to it, along
+/// the lines of TCP's (RFC 6298): partitioned neighbors are probed less and less often, while
+/// healthy ones are retried soon after an ack is overdue.
+pub struct Peer {
+    /// the length of the log prefix the neighbor acked
+    acked: usize,
+    /// whether the neighbor is sent keys rather than their positions, always in flood mode
+    eager: bool,
+    /// the latest gossip that was not acked yet
+    pending: Option<Pending>,
+    /// when the neighbor last acked gossip, which tells whether it is reachable
+    last_ack: Option<Instant>,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+struct Pending {
+    end: usize,
+    sent_at: Instant,
+    /// acks of retransmissions are ambiguous and not used as rtt samples (Karn's algorithm)
+    retransmitted: bool,
+}
+
+impl Default for Peer {
+    fn default() -> Self {
+        Self {
+            acked: 0,
+            eager: true,
+            pending: None,
+            last_ack: None,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: Self::INITIAL_RTO,
+        }
+    }
+}
+
+impl Peer {
+    pub const INITIAL_RTO: Duration = Duration::from_millis(500);
+    const MIN_RTO: Duration = Duration::from_millis(20);
+    const MAX_RTO: Duration = Duration::from_secs(4);
+
+    /// Whether to send the keys up to `end` now: right away if nothing is pending, after the
+    /// rto otherwise. New keys since are sent after [`GOSSIP_INTERVAL`] without waiting for the
+    /// rto, but only while the neighbor acked within the last rto, so partitioned neighbors
+    /// still back off.
+    fn due(&mut self, end: usize, now: Instant) -> bool {
+        let responsive = self
+            .last_ack
+            .is_some_and(|last_ack| now < last_ack + self.rto);
+        let retransmitted = match &self.pending {
+            None => false,
+            Some(pending)
+                if responsive && end > pending.end && now >= pending.sent_at + GOSSIP_INTERVAL =>
+            {
+                false
+            }
+            Some(pending) if now >= pending.sent_at + self.rto => {
+                self.rto = (self.rto * 2).min(Self::MAX_RTO);
+                true
+            }
+            Some(_) => return false,
+        };
+        self.pending = Some(Pending {
+            end,
+            sent_at: now,
+            retransmitted,
+        });
+        true
+    }
+
+    fn ack(&mut self, end: usize, now: Instant) {
+        self.acked = self.acked.max(end);
+        self.last_ack = Some(now);
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        if end == pending.end && !pending.retransmitted {
+            self.sample(now - pending.sent_at);
+        }
+        if end < pending.end {
+            self.pending = Some(pending);
+        }
+    }
+
+    fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        let rto = self.srtt.unwrap_or_default() + self.rttvar * 4;
+        self.rto = rto.clamp(Self::MIN_RTO, Self::MAX_RTO);
+    }
+}
+
+struct Entry {
+    position: u64,
+    received_at: Instant,
+    /// the neighbor that gossiped the key, which never needs it back
+    from: Option<String>,
+    /// how many other neighbors have not acked the key yet, or `None` once its coverage was
+    /// recorded or if there were no neighbors when it arrived
+    awaiting: Option<usize>,
+}
+
+impl Replica {
+    /// how long an announced key may take to arrive through the tree before it is grafted
+    const GRAFT_TIMEOUT: Duration = Duration::from_millis(250);
+
+    pub fn new(mode: Mode, order: Order) -> Self {
+        Self {
+            mode,
+            causal: (order == Order::Causal).then(Causal::default),
+            total: (order == Order::Total).then(Total::default),
+            ..Default::default()
+        }
+    }
+
+    /// returns whether the key is new
+    pub fn insert(&mut self, message: Value, from: Option<&str>) -> bool {
+        let position = position(&message);
+        if self.index.contains_key(&position) {
+            self.stats.duplicates += u64::from(from.is_some());
+            return false;
+        }
+        self.announced.remove(&position);
+        if let Mode::PushPull { .. } = self.mode {
+            self.rumors.insert(position, 0);
+        }
+        self.index.insert(position, self.log.len());
+        let now = Instant::now();
+        if let Some(causal) = &mut self.causal {
+            causal.receive(&message);
+        }
+        if let Some(total) = &mut self.total {
+            total.receive(&message);
+        }
+        self.log.push(message);
+        let awaiting = self
+            .peers
+            .keys()
+            .filter(|neighbor| Some(neighbor.as_str()) != from)
+            .count();
+        if awaiting == 0 && !self.peers.is_empty() {
+            // the only neighbor sent the key itself
+            self.stats.cover(Duration::ZERO);
+        }
+        self.unsettled.push_back(Entry {
+            position,
+            received_at: now,
+            from: from.map(String::from),
+            awaiting: (awaiting > 0).then_some(awaiting),
+        });
+        self.settle();
+        true
+    }
+
+    /// Moves `neighbor`'s mark from `start` up to `end` and records the coverage of every key it
+    /// was the last neighbor to ack.
+    fn acked_up_to(&mut self, neighbor: &str, start: usize, end: usize, now: Instant) {
+        for idx in start.max(self.covered)..end {
+            let Some(entry) = self.unsettled.get_mut(idx - self.covered) else {
+                break;
+            };
+            if entry.from.as_deref() == Some(neighbor) {
+                continue;
+            }
+            let Some(awaiting) = &mut entry.awaiting else {
+                continue;
+            };
+            *awaiting -= 1;
+            if *awaiting == 0 {
+                entry.awaiting = None;
+                let elapsed = now.saturating_duration_since(entry.received_at);
+                self.stats.cover(elapsed);
+            }
+        }
+    }
+
+    /// settles the prefix of the log every neighbor acked
+    fn settle(&mut self) {
+        let covered = self
+            .peers
+            .values()
+            .map(|peer| peer.acked)
+            .min()
+            .unwrap_or(self.log.len());
+        while self.covered < covered && self.unsettled.pop_front().is_some() {
+            self.covered += 1;
+        }
+    }
+
+    /// The keys `neighbor` did not ack yet, with their positions. Only neighbors that joined
+    /// after keys settled need those, and they are sent them all, whoever gossiped them.
+    fn missing<'a>(&'a self, neighbor: &'a str) -> impl Iterator<Item = (u64, &'a Value)> + 'a {
+        let start = self.peers.get(neighbor).map_or(0, |peer| peer.acked);
+        (start..self.log.len()).filter_map(move |idx| {
+            let message = &self.log[idx];
+            let entry = idx
+                .checked_sub(self.covered)
+                .and_then(|offset| self.unsettled.get(offset));
+            match entry {
+                Some(entry) if entry.from.as_deref() == Some(neighbor) => None,
+                Some(entry) => Some((entry.position, message)),
+                None => Some((position(message), message)),
+            }
+        })
+    }
+
+    /// Handles gossip from `neighbor` of which no key was new. In plumtree mode this means the
+    /// neighbor is a redundant link of the tree, so it becomes lazy and should be told to prune
+    /// us as well, which is what the return value says.
+    pub fn received_duplicates(&mut self, neighbor: &str) -> bool {
+        match self.peers.get_mut(neighbor) {
+            Some(peer) if self.mode == Mode::Plumtree && peer.eager => {
+                peer.eager = false;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn prune(&mut self, neighbor: &str) {
+        if let Some(peer) = self.peers.get_mut(neighbor) {
+            peer.eager = false;
+        }
+    }
+
+    /// makes `neighbor` eager again and returns the keys it asked for
+    pub fn graft(&mut self, neighbor: &str, positions: Vec<u64>) -> Vec<Value> {
+        if let Some(peer) = self.peers.get_mut(neighbor) {
+            peer.eager = true;
+        }
+        positions
+            .into_iter()
+            .filter_map(|position| self.index.get(&position))
+            .map(|&idx| self.log[idx].clone())
+            .collect()
+    }
+
+    pub fn announce(&mut self, neighbor: &str, positions: Vec<u64>, now: Instant) {
+        for position in positions {
+            if !self.index.contains_key(&position) {
+                self.announced
+                    .entry(position)
+                    .or_insert_with(|| Announcement {
+                        from: neighbor.to_string(),
+                        deadline: now + Self::GRAFT_TIMEOUT,
+                    });
+            }
+        }
+    }
+
+    /// The announced keys that are overdue, by the neighbor to graft them from. Those neighbors
+    /// become eager, and the keys get another timeout in case the graft gets lost too.
+    pub fn overdue(&mut self, now: Instant) -> HashMap<String, Vec<u64>> {
+        let mut grafts: HashMap<String, Vec<u64>> = HashMap::new();
+        for (&position, announcement) in &mut self.announced {
+            if announcement.deadline <= now {
+                announcement.deadline = now + Self::GRAFT_TIMEOUT;
+                grafts
+                    .entry(announcement.from.clone())
+                    .or_default()
+                    .push(position);
+            }
+        }
+        for neighbor in grafts.keys() {
+            if let Some(peer) = self.peers.get_mut(neighbor) {
+                peer.eager = true;
+            }
+        }
+        grafts
+    }
+
+    /// The values to forward to the sequencer again, with its id and epoch. The values this
+    /// node forwarded before it became the sequencer itself are sequenced here instead.
+    pub fn overdue_forwards(
+        &mut self,
+        now: Instant,
+    ) -> anyhow::Result<Option<(String, u64, Vec<Value>)>> {
+        let Some(total) = &mut self.total else {
+            return Ok(None);
+        };
+        let messages = total.overdue(now);
+        if !total.is_sequencer() {
+            return Ok(Some((total.sequencer().to_string(), total.epoch, messages)));
+        }
+        for message in messages {
+            let key = match &mut self.total {
+                Some(total) => total.sequence(message)?,
+                None => None,
+            };
+            if let Some(key) = key {
+                self.insert(key, None);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Replaces the neighbors gossip goes to. New neighbors have not acked anything, so they are
+    /// sent the whole log, while removed ones are no longer waited on.
+    pub fn set_neighbors(&mut self, neighbors: Vec<String>) {
+        let now = Instant::now();
+        let removed = self
+            .peers
+            .iter()
+            .filter(|(neighbor, _)| !neighbors.contains(neighbor))
+            .map(|(neighbor, peer)| (neighbor.clone(), peer.acked))
+            .collect::<Vec<_>>();
+        for (neighbor, acked) in removed {
+            self.peers.remove(&neighbor);
+            self.acked_up_to(&neighbor, acked, self.log.len(), now);
+        }
+        for neighbor in neighbors {
+            if self.peers.contains_key(&neighbor) {
+                continue;
+            }
+            for entry in &mut self.unsettled {
+                if entry.from.as_ref() != Some(&neighbor) {
+                    if let Some(awaiting) = &mut entry.awaiting {
+                        *awaiting += 1;
+                    }
+                }
+            }
+            self.peers.insert(neighbor, Peer::default());
+        }
+        self.settle();
+    }
+
+    /// the rumors to push this round, which age by one and are dropped after `lifetime` rounds
+    pub fn rumors(&mut self, lifetime: u32) -> Vec<Value> {
+        self.rumors.retain(|_, age| {
+            *age += 1;
+            *age <= lifetime
+        });
+        self.rumors
+            .keys()
+            .map(|position| self.log[self.index[position]].clone())
+            .collect()
+    }
+
+    /// The gossip to send `neighbor` now, with the `end` it should ack, if any is due. Keys are
+    /// only cloned once the send is due, not on every tick that waits for it.
+    pub fn outgoing(&mut self, neighbor: &str, now: Instant) -> Option<(Outgoing, usize)> {
+        let end = self.log.len();
+        if self.missing(neighbor).next().is_none() {
+            // whatever the neighbor did not ack it sent us itself
+            let peer = self.peers.get_mut(neighbor)?;
+            let start = std::mem::replace(&mut peer.acked, end);
+            peer.pending = None;
+            self.acked_up_to(neighbor, start, end, now);
+            self.settle();
+            return None;
+        }
+        let peer = self.peers.get_mut(neighbor)?;
+        if !peer.due(end, now) {
+            return None;
+        }
+        let retransmit = peer
+            .pending
+            .as_ref()
+            .is_some_and(|pending| pending.retransmitted);
+        let outgoing = match peer.eager {
+            true => Outgoing::Keys {
+                messages: self
+                    .missing(neighbor)
+                    .map(|(_, message)| message.clone())
+                    .collect(),
+                retransmit,
+            },
+            false => Outgoing::Positions(
+                self.missing(neighbor)
+                    .map(|(position, _)| position)
+                    .collect(),
+            ),
+        };
+        Some((outgoing, end))
+    }
+
+    /// Gossip always carries every key from the neighbor's mark up to `end`, so an ack for `end`
+    /// confirms the whole prefix even if earlier gossip got lost.
+    pub fn ack(&mut self, neighbor: String, end: usize, now: Instant) {
+        let end = end.min(self.log.len());
+        if let Some(peer) = self.peers.get_mut(&neighbor) {
+            let start = peer.acked;
+            peer.ack(end, now);
+            let acked = peer.acked;
+            self.acked_up_to(&neighbor, start, acked, now);
+            self.settle();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::reconcile::Rounds;
+
+    #[test]
+    fn retransmissions_back_off_until_acked() {
+        let mut peer = Peer::default();
+        let start = Instant::now();
+        assert!(peer.due(1, start));
+        assert!(!peer.due(1, start + Peer::INITIAL_RTO / 2));
+        assert!(peer.due(1, start + Peer::INITIAL_RTO));
+        assert_eq!(peer.rto, Peer::INITIAL_RTO * 2);
+        assert!(!peer.due(1, start + Peer::INITIAL_RTO * 2));
+
+        // the ack of a retransmission is no rtt sample
+        peer.ack(1, start + Peer::INITIAL_RTO * 2);
+        assert_eq!((peer.acked, peer.srtt), (1, None));
+
+        let sent_at = start + Peer::INITIAL_RTO * 3;
+        assert!(peer.due(2, sent_at));
+        peer.ack(2, sent_at + Duration::from_millis(30));
+        assert_eq!(peer.srtt, Some(Duration::from_millis(30)));
+        assert_eq!(peer.rto, Duration::from_millis(90));
+
+        // new keys skip the rto only while the neighbor keeps acking
+        let sent_at = sent_at + Duration::from_millis(60);
+        assert!(peer.due(3, sent_at));
+        assert!(peer.due(4, sent_at + GOSSIP_INTERVAL));
+        assert!(!peer.due(5, sent_at + GOSSIP_INTERVAL * 2));
+        assert!(peer.due(5, sent_at + GOSSIP_INTERVAL + peer.rto));
+        assert_eq!(peer.rto, Duration::from_millis(180));
+    }
+
+    #[test]
+    fn new_keys_do_not_bypass_the_backoff() {
+        let mut peer = Peer::default();
+        let start = Instant::now();
+        assert!(peer.due(1, start));
+        assert!(!peer.due(2, start + GOSSIP_INTERVAL));
+        assert!(peer.due(3, start + Peer::INITIAL_RTO));
+        assert!(!peer.due(4, start + Peer::INITIAL_RTO + GOSSIP_INTERVAL));
+        assert!(!peer.due(4, start + Peer::INITIAL_RTO * 2));
+        assert!(peer.due(4, start + Peer::INITIAL_RTO * 3));
+    }
+
+    #[test]
+    fn rumors_age_out() {
+        let mode = "push-pull:2:50".parse().unwrap();
+        assert_eq!(
+            mode,
+            Mode::PushPull {
+                fanout: 2,
+                round: Duration::from_millis(50)
+            }
+        );
+        let mut replica = Replica::new(mode, Order::Unordered);
+        replica.insert(json!(1), None);
+        assert_eq!(replica.rumors(2), [json!(1)]);
+        replica.insert(json!(2), None);
+        assert_eq!(replica.rumors(2).len(), 2);
+        assert_eq!(replica.rumors(2), [json!(2)]);
+        assert!(replica.rumors(2).is_empty());
+
+        // pushing to two peers triples the informed nodes every round, so 27 take three rounds
+        let members = (1..27).map(|idx| format!("n{idx}")).collect();
+        let rounds = Rounds::new("n0", members, 2, Duration::from_millis(50), 0);
+        assert_eq!(rounds.lifetime, 3 + Rounds::MARGIN);
+    }
+
+    #[test]
+    fn acked_keys_are_settled() {
+        let mut replica = Replica::default();
+        replica.set_neighbors(vec!["n1".to_string()]);
+        let now = Instant::now();
+        replica.insert(json!(1), None);
+        replica.insert(json!(2), Some("n1"));
+        replica.insert(json!(3), None);
+        assert_eq!(replica.missing("n1").count(), 2);
+        replica.ack("n1".to_string(), 2, now);
+        assert_eq!((replica.covered, replica.unsettled.len()), (2, 1));
+
+        // a neighbor that joins later is sent settled keys too
+        replica.set_neighbors(vec!["n1".to_string(), "n2".to_string()]);
+        assert_eq!(replica.missing("n2").count(), 3);
+        replica.ack("n1".to_string(), 3, now);
+        replica.ack("n2".to_string(), 3, now);
+        assert!(replica.unsettled.is_empty());
+        assert_eq!(replica.stats.covered, 3);
+    }
+
+    #[test]
+    fn coverage_is_recorded_per_key() {
+        let mut replica = Replica::default();
+        replica.insert(json!(0), None);
+        replica.set_neighbors(vec!["n1".to_string(), "n2".to_string()]);
+        let now = Instant::now();
+        replica.insert(json!(1), None);
+        replica.insert(json!(2), Some("n1"));
+
+        // n1 sent the second key, so n2 is the last to ack it even while n1 lags behind
+        replica.ack("n2".to_string(), 3, now + Duration::from_millis(100));
+        assert_eq!(replica.stats.covered, 1);
+        replica.ack("n1".to_string(), 3, now + Duration::from_secs(1));
+        assert_eq!(replica.stats.covered, 2);
+        let fast = replica.stats.coverage_total - replica.stats.coverage_max;
+        assert!(fast < Duration::from_millis(200), "{fast:?}");
+        assert!(replica.unsettled.is_empty());
+    }
+
+    #[test]
+    fn keys_are_deduplicated_by_content() {
+        let mut replica = Replica::default();
+        replica.insert(json!({ "a": 1, "b": [2, "3"] }), None);
+        replica.insert(
+            serde_json::from_str(r#"{"b": [2, "3"], "a": 1}"#).unwrap(),
+            None,
+        );
+        replica.insert(json!("1"), None);
+        replica.insert(json!(1), None);
+        assert_eq!(replica.log.len(), 3);
+    }
+}
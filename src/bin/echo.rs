@@ -27,8 +27,12 @@ impl<W> Node<W, InPayload> for EchoNode<W>
 where
     W: std::io::Write + Send + Sync,
 {
-    fn new(_node_id: String, _neighbors: Vec<String>, serializer: MessageSerializer<W>) -> Self {
-        Self { serializer }
+    fn new(
+        _node_id: String,
+        _neighbors: Vec<String>,
+        serializer: MessageSerializer<W>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self { serializer })
     }
 
     fn process(&mut self, in_msg: InMessage<InPayload>) -> anyhow::Result<()> {
@@ -1,13 +1,16 @@
 use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, bail, Context};
+use env_logger::Target;
+use log::LevelFilter;
 use maelstrom::{
-    run_node, Body, DeconstructedInMessage, InMessage, MessageSerializer, Node, OutMessage,
-    PartialInMessage,
+    env_config, expire_store_requests, run_node, Body, DeconstructedInMessage, ErrorCode,
+    InMessage, MessageSerializer, Node, OutMessage, PartialInMessage, StoreClient, StoreRequest,
 };
 use serde::{Deserialize, Serialize};
 
@@ -15,18 +18,342 @@ use serde::{Deserialize, Serialize};
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 enum InPayload {
-    Add { delta: usize },
+    Add { delta: i64 },
     Read,
-    Broadcast { sum: usize },
+    Broadcast(Totals),
+    ReadOk { value: i64 },
+    WriteOk,
+    CasOk,
+    Error { code: ErrorCode, text: String },
 }
 
 #[derive(Copy, Clone, Serialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
-enum OutPayload {
+enum OutPayload<'a> {
     AddOk,
-    ReadOk { value: usize },
-    Broadcast { sum: usize },
+    ReadOk {
+        value: i64,
+    },
+    Broadcast(Totals),
+    Error {
+        code: ErrorCode,
+        text: &'a str,
+    },
+    Read {
+        key: &'a str,
+    },
+    Write {
+        key: &'a str,
+        value: &'a str,
+    },
+    Cas {
+        key: &'a str,
+        from: i64,
+        to: i64,
+        create_if_not_exists: bool,
+    },
+}
+
+/// Where the counter is kept, set through the `COUNTER_STORE` environment variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum CounterStore {
+    /// every node's [`Totals`], gossiped to the others
+    #[default]
+    Gossip,
+    /// a single value in seq-kv, see [`KvCounter`]
+    SeqKv,
+}
+
+impl FromStr for CounterStore {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gossip" => Ok(Self::Gossip),
+            "seq-kv" => Ok(Self::SeqKv),
+            _ => bail!("expected gossip or seq-kv"),
+        }
+    }
+}
+
+/// The sums of the positive and the negative deltas added on one node, which makes the counter a
+/// PN-counter: both sums only grow, so copies of them merge by taking the maximum of each, even
+/// when broadcasts arrive out of order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Totals {
+    increments: u64,
+    decrements: u64,
+}
+
+impl Totals {
+    fn add(&mut self, delta: i64) {
+        let sum = match delta < 0 {
+            true => &mut self.decrements,
+            false => &mut self.increments,
+        };
+        *sum = sum.saturating_add(delta.unsigned_abs());
+    }
+
+    fn merge(&mut self, other: Totals) {
+        self.increments = self.increments.max(other.increments);
+        self.decrements = self.decrements.max(other.decrements);
+    }
+
+    fn value(self) -> i128 {
+        i128::from(self.increments) - i128::from(self.decrements)
+    }
+}
+
+/// Keeps the counter in seq-kv under a single key. Adds are batched into one `cas` from the last
+/// value seen, which is read again whenever the `cas` fails because another node got there
+/// first. Seq-kv may serve reads from an earlier state, so every read, of a batch of client
+/// reads or after a failed `cas`, first writes a unique sentinel, which orders the read after
+/// everything that happened before the write. Batches of adds and reads take turns, so neither
+/// starves the other.
+struct KvCounter {
+    node_id: String,
+    adds: Vec<(PartialInMessage, i64)>,
+    reads: Vec<PartialInMessage>,
+    store: StoreClient<Step>,
+    /// our best guess of the value in the store, the `from` of the next `cas`
+    last_seen: i64,
+    sentinels: u64,
+    /// whether the waiting reads go before the waiting adds
+    reads_first: bool,
+}
+
+/// what a request to the store is for, with the clients waiting on it
+enum Step {
+    /// adding the batched deltas, which makes the store hold `to`
+    Cas {
+        adds: Vec<(PartialInMessage, i64)>,
+        to: i64,
+    },
+    /// reading the value after a failed `cas`, to try again from there
+    Refresh(Vec<(PartialInMessage, i64)>),
+    /// writing a sentinel before the read that follows, either `Refresh` or `Read`
+    Sentinel(Box<Step>),
+    Read(Vec<PartialInMessage>),
+}
+
+impl Step {
+    fn into_clients(self) -> Vec<PartialInMessage> {
+        match self {
+            Step::Cas { adds, .. } | Step::Refresh(adds) => adds
+                .into_iter()
+                .map(|(partial_in_msg, _)| partial_in_msg)
+                .collect(),
+            Step::Sentinel(step) => step.into_clients(),
+            Step::Read(reads) => reads,
+        }
+    }
+}
+
+impl KvCounter {
+    const SERVICE: &'static str = "seq-kv";
+    const KEY: &'static str = "counter";
+    const SENTINEL_KEY: &'static str = "counter-sentinel";
+    const STORE_TIMEOUT: Duration = Duration::from_millis(500);
+
+    fn new(node_id: String) -> Self {
+        Self {
+            store: StoreClient::new(node_id.clone(), Self::SERVICE, Self::STORE_TIMEOUT),
+            node_id,
+            adds: Vec::new(),
+            reads: Vec::new(),
+            last_seen: 0,
+            sentinels: 0,
+            reads_first: false,
+        }
+    }
+
+    /// sends the next request to the store unless one is in flight, alternating between adds
+    /// and reads while both are waiting
+    fn next<W>(&mut self, serializer: &mut MessageSerializer<W>) -> anyhow::Result<()>
+    where
+        W: std::io::Write + Send + Sync,
+    {
+        if self.store.is_busy() {
+            return Ok(());
+        }
+        if !self.reads.is_empty() && (self.reads_first || self.adds.is_empty()) {
+            self.reads_first = false;
+            let reads = std::mem::take(&mut self.reads);
+            return self.sentinel(serializer, Step::Read(reads));
+        }
+        if !self.adds.is_empty() {
+            self.reads_first = true;
+            let adds = std::mem::take(&mut self.adds);
+            return self.cas(serializer, adds);
+        }
+        Ok(())
+    }
+
+    /// writes a unique sentinel, then reads the counter for `step`
+    fn sentinel<W>(
+        &mut self,
+        serializer: &mut MessageSerializer<W>,
+        step: Step,
+    ) -> anyhow::Result<()>
+    where
+        W: std::io::Write + Send + Sync,
+    {
+        self.sentinels += 1;
+        let value = format!("{}-{}", self.node_id, self.sentinels);
+        let payload = OutPayload::Write {
+            key: Self::SENTINEL_KEY,
+            value: &value,
+        };
+        self.store
+            .send(serializer, payload, Step::Sentinel(Box::new(step)))
+    }
+
+    fn cas<W>(
+        &mut self,
+        serializer: &mut MessageSerializer<W>,
+        adds: Vec<(PartialInMessage, i64)>,
+    ) -> anyhow::Result<()>
+    where
+        W: std::io::Write + Send + Sync,
+    {
+        let to = adds
+            .iter()
+            .try_fold(self.last_seen, |sum, (_, delta)| sum.checked_add(*delta));
+        let Some(to) = to else {
+            let text = "the counter would overflow";
+            Self::fail(serializer, Step::Refresh(adds), ErrorCode::Abort, text)?;
+            return self.next(serializer);
+        };
+        let payload = OutPayload::Cas {
+            key: Self::KEY,
+            from: self.last_seen,
+            to,
+            create_if_not_exists: true,
+        };
+        self.store.send(serializer, payload, Step::Cas { adds, to })
+    }
+
+    fn handle_reply<W>(
+        &mut self,
+        in_reply_to: Option<usize>,
+        payload: InPayload,
+        serializer: &mut MessageSerializer<W>,
+    ) -> anyhow::Result<()>
+    where
+        W: std::io::Write + Send + Sync,
+    {
+        // replies to requests that already timed out are stale
+        let Some(request) = self.store.take_reply(in_reply_to) else {
+            return Ok(());
+        };
+        match (request.purpose, payload) {
+            (Step::Cas { adds, to }, InPayload::CasOk) => {
+                self.last_seen = to;
+                for (partial_in_msg, _) in adds {
+                    reply(serializer, partial_in_msg, OutPayload::AddOk)?;
+                }
+            }
+            (
+                Step::Cas { adds, .. },
+                InPayload::Error {
+                    code: ErrorCode::PreconditionFailed,
+                    ..
+                },
+            ) => return self.sentinel(serializer, Step::Refresh(adds)),
+            (Step::Refresh(adds), InPayload::ReadOk { value }) => {
+                self.last_seen = value;
+                return self.cas(serializer, adds);
+            }
+            (Step::Sentinel(step), InPayload::WriteOk) => {
+                let payload = OutPayload::Read { key: Self::KEY };
+                return self.store.send(serializer, payload, *step);
+            }
+            (Step::Read(reads), InPayload::ReadOk { value }) => {
+                self.last_seen = value;
+                for partial_in_msg in reads {
+                    reply(serializer, partial_in_msg, OutPayload::ReadOk { value })?;
+                }
+            }
+            (
+                Step::Read(reads),
+                InPayload::Error {
+                    code: ErrorCode::KeyDoesNotExist,
+                    ..
+                },
+            ) => {
+                for partial_in_msg in reads {
+                    reply(serializer, partial_in_msg, OutPayload::ReadOk { value: 0 })?;
+                }
+            }
+            // the cas may still have been applied, so the adds are indeterminate
+            (step @ Step::Cas { .. }, InPayload::Error { code, text }) => {
+                log::warn!("seq-kv failed a cas with {code:?}: {text}");
+                Self::fail(serializer, step, ErrorCode::Crash, "seq-kv failed a cas")?;
+            }
+            (step, InPayload::Error { code, text }) => {
+                log::warn!("seq-kv failed with {code:?}: {text}");
+                let code = ErrorCode::TemporarilyUnavailable;
+                Self::fail(serializer, step, code, "seq-kv is unavailable")?;
+            }
+            // left for the timeout to fail, as if the reply had been lost
+            (step, _) => {
+                log::warn!("ignoring unexpected seq-kv reply to msg {}", request.msg_id);
+                let purpose = step;
+                self.store.restore(StoreRequest { purpose, ..request });
+                return Ok(());
+            }
+        }
+        self.next(serializer)
+    }
+
+    /// Gives up on a request to the store that went unanswered for too long. A `cas` may still
+    /// have been applied, so the error is a timeout, which does not promise that it was not.
+    fn expire<W>(&mut self, serializer: &mut MessageSerializer<W>) -> anyhow::Result<()>
+    where
+        W: std::io::Write + Send + Sync,
+    {
+        let Some(request) = self.store.take_expired() else {
+            return Ok(());
+        };
+        let text = "seq-kv did not reply";
+        Self::fail(serializer, request.purpose, ErrorCode::Timeout, text)?;
+        self.next(serializer)
+    }
+
+    fn fail<W>(
+        serializer: &mut MessageSerializer<W>,
+        step: Step,
+        code: ErrorCode,
+        text: &str,
+    ) -> anyhow::Result<()>
+    where
+        W: std::io::Write + Send + Sync,
+    {
+        for partial_in_msg in step.into_clients() {
+            reply(serializer, partial_in_msg, OutPayload::Error { code, text })?;
+        }
+        Ok(())
+    }
+}
+
+fn reply<W>(
+    serializer: &mut MessageSerializer<W>,
+    partial_in_msg: PartialInMessage,
+    payload: OutPayload,
+) -> anyhow::Result<()>
+where
+    W: std::io::Write + Send + Sync,
+{
+    let mut out_msg = partial_in_msg.to_out_msg(payload);
+    serializer
+        .send(&mut out_msg)
+        .context("failed to serialize reply")
+}
+
+enum Counter {
+    Gossip(Arc<Mutex<HashMap<String, Totals>>>),
+    SeqKv(Arc<Mutex<KvCounter>>),
 }
 
 struct CounterNode<W>
@@ -35,7 +362,8 @@ where
 {
     node_id: String,
     serializer: Arc<Mutex<MessageSerializer<W>>>,
-    map: Arc<Mutex<HashMap<String, usize>>>,
+    counter: Counter,
+    /// the broadcast thread, or the thread expiring seq-kv requests
     handle: JoinHandle<anyhow::Result<()>>,
     tx: Sender<bool>,
 }
@@ -44,44 +372,52 @@ impl<W> Node<W, InPayload> for CounterNode<W>
 where
     W: std::io::Write + Send + Sync,
 {
-    fn new(node_id: String, neighbors: Vec<String>, serializer: MessageSerializer<W>) -> Self {
-        let serializer = Arc::new(Mutex::new(serializer));
-        let map = Arc::new(Mutex::new(HashMap::from_iter(vec![(node_id.clone(), 0)])));
-        let (tx, rx) = mpsc::channel();
-        let handle = {
-            let node_id = node_id.clone();
-            let serializer = Arc::clone(&serializer);
-            let map = Arc::clone(&map);
-            thread::spawn(move || {
-                broadcast(
-                    node_id,
-                    map,
-                    serializer,
-                    neighbors,
-                    rx,
-                    CounterNode::<W>::REPLICATE_SLEEP_TIME,
-                )
-            })
-        };
-        Self {
-            node_id,
-            serializer,
-            map,
-            handle,
-            tx,
-        }
+    fn new(
+        node_id: String,
+        neighbors: Vec<String>,
+        serializer: MessageSerializer<W>,
+    ) -> anyhow::Result<Self> {
+        let store = env_config("COUNTER_STORE")?.unwrap_or_default();
+        Ok(Self::with_store(node_id, neighbors, serializer, store))
     }
 
     fn process(&mut self, in_msg: InMessage<InPayload>) -> anyhow::Result<()> {
+        let in_reply_to = in_msg.body.in_reply_to;
         let DeconstructedInMessage {
             partial_in_msg,
             in_payload,
         } = in_msg.into();
+        let counter = match &self.counter {
+            Counter::Gossip(_) => {
+                return match in_payload {
+                    InPayload::Add { delta } => self.handle_add_msg(partial_in_msg, delta),
+                    InPayload::Read => self.handle_read_msg(partial_in_msg),
+                    InPayload::Broadcast(totals) => {
+                        self.handle_broadcast_msg(partial_in_msg.src, totals)
+                    }
+                    _ => {
+                        log::warn!("ignoring seq-kv reply to msg {in_reply_to:?} in gossip mode");
+                        Ok(())
+                    }
+                };
+            }
+            Counter::SeqKv(counter) => counter,
+        };
+        let mut counter = lock_counter(counter)?;
+        let mut serializer = lock_serializer(&self.serializer)?;
         match in_payload {
-            InPayload::Add { delta } => self.handle_add_msg(partial_in_msg, delta),
-            InPayload::Read => self.handle_read_msg(partial_in_msg),
-            InPayload::Broadcast { sum } => self.handle_broadcast_msg(partial_in_msg.src, sum),
+            InPayload::Add { delta } => counter.adds.push((partial_in_msg, delta)),
+            InPayload::Read => counter.reads.push(partial_in_msg),
+            InPayload::Broadcast(_) => {
+                log::warn!(
+                    "ignoring broadcast from {} in seq-kv mode",
+                    partial_in_msg.src
+                );
+                return Ok(());
+            }
+            reply => return counter.handle_reply(in_reply_to, reply, &mut serializer),
         }
+        counter.next(&mut serializer)
     }
 
     fn shutdown(self) -> anyhow::Result<()> {
@@ -99,14 +435,73 @@ where
     W: std::io::Write + Send + Sync,
 {
     const REPLICATE_SLEEP_TIME: Duration = Duration::from_millis(5);
+    const EXPIRE_SLEEP_TIME: Duration = Duration::from_millis(50);
+
+    fn with_store(
+        node_id: String,
+        neighbors: Vec<String>,
+        serializer: MessageSerializer<W>,
+        store: CounterStore,
+    ) -> Self {
+        let serializer = Arc::new(Mutex::new(serializer));
+        let (tx, rx) = mpsc::channel();
+        let (counter, handle) = match store {
+            CounterStore::Gossip => {
+                let map = Arc::new(Mutex::new(HashMap::from_iter(vec![(
+                    node_id.clone(),
+                    Totals::default(),
+                )])));
+                let handle = {
+                    let node_id = node_id.clone();
+                    let serializer = Arc::clone(&serializer);
+                    let map = Arc::clone(&map);
+                    thread::spawn(move || {
+                        broadcast(
+                            node_id,
+                            map,
+                            serializer,
+                            neighbors,
+                            rx,
+                            Self::REPLICATE_SLEEP_TIME,
+                        )
+                    })
+                };
+                (Counter::Gossip(map), handle)
+            }
+            CounterStore::SeqKv => {
+                let counter = Arc::new(Mutex::new(KvCounter::new(node_id.clone())));
+                let handle = {
+                    let counter = Arc::clone(&counter);
+                    let serializer = Arc::clone(&serializer);
+                    thread::spawn(move || {
+                        expire_store_requests(
+                            counter,
+                            serializer,
+                            rx,
+                            Self::EXPIRE_SLEEP_TIME,
+                            KvCounter::expire,
+                        )
+                    })
+                };
+                (Counter::SeqKv(counter), handle)
+            }
+        };
+        Self {
+            node_id,
+            serializer,
+            counter,
+            handle,
+            tx,
+        }
+    }
 
     fn handle_add_msg(
         &mut self,
         partial_in_msg: PartialInMessage,
-        delta: usize,
+        delta: i64,
     ) -> anyhow::Result<()> {
-        if let Some(sum) = self.lock_map()?.get_mut(&self.node_id) {
-            *sum += delta;
+        if let Some(totals) = self.lock_map()?.get_mut(&self.node_id) {
+            totals.add(delta);
         }
         let mut out_msg = partial_in_msg.to_out_msg(OutPayload::AddOk);
         self.lock_serializer()?
@@ -115,32 +510,40 @@ where
     }
 
     fn handle_read_msg(&self, partial_in_msg: PartialInMessage) -> anyhow::Result<()> {
-        let sum = self.lock_map()?.values().sum::<usize>();
-        let payload = OutPayload::ReadOk { value: sum };
+        let sum = self
+            .lock_map()?
+            .values()
+            .map(|totals| totals.value())
+            .sum::<i128>();
+        let value = sum.clamp(i64::MIN.into(), i64::MAX.into()) as i64;
+        let payload = OutPayload::ReadOk { value };
         let mut out_msg = partial_in_msg.to_out_msg(payload);
         self.lock_serializer()?
             .send(&mut out_msg)
             .context("failed to serialize read_ok message")
     }
 
-    fn handle_broadcast_msg(&self, node_id: String, sum: usize) -> anyhow::Result<()> {
-        self.lock_map()?.insert(node_id, sum);
+    fn handle_broadcast_msg(&self, node_id: String, totals: Totals) -> anyhow::Result<()> {
+        self.lock_map()?.entry(node_id).or_default().merge(totals);
         Ok(())
     }
 
-    fn lock_map(&self) -> anyhow::Result<MutexGuard<HashMap<String, usize>>> {
-        lock_map(&self.map)
+    fn lock_map(&self) -> anyhow::Result<MutexGuard<'_, HashMap<String, Totals>>> {
+        match &self.counter {
+            Counter::Gossip(map) => lock_map(map),
+            Counter::SeqKv(_) => bail!("there are no totals in seq-kv mode"),
+        }
     }
 
-    fn lock_serializer(&self) -> anyhow::Result<MutexGuard<MessageSerializer<W>>> {
+    fn lock_serializer(&self) -> anyhow::Result<MutexGuard<'_, MessageSerializer<W>>> {
         lock_serializer(&self.serializer)
     }
 }
 
-/// runs on a seperate thread and informs other nodes about the current sum
+/// runs on a separate thread and informs other nodes about the current totals
 fn broadcast<W>(
     node_id: String,
-    map: Arc<Mutex<HashMap<String, usize>>>,
+    map: Arc<Mutex<HashMap<String, Totals>>>,
     serializer: Arc<Mutex<MessageSerializer<W>>>,
     neighbors: Vec<String>,
     rx: Receiver<bool>,
@@ -151,9 +554,9 @@ where
 {
     while rx.try_recv().is_err() {
         thread::sleep(sleep_time);
-        let sum = *lock_map(&map)?
-            .get(&node_id)
-            .ok_or_else(|| anyhow!("map does not contain the sum of self node_id: {node_id:?}"))?;
+        let totals = *lock_map(&map)?.get(&node_id).ok_or_else(|| {
+            anyhow!("map does not contain the totals of self node_id: {node_id:?}")
+        })?;
         let mut serializer = lock_serializer(&serializer)?;
         for neighbor in neighbors.iter() {
             let mut out_msg = OutMessage {
@@ -162,7 +565,7 @@ where
                 body: Body {
                     msg_id: None,
                     in_reply_to: None,
-                    payload: OutPayload::Broadcast { sum },
+                    payload: OutPayload::Broadcast(totals),
                 },
             };
             serializer
@@ -173,16 +576,22 @@ where
     Ok(())
 }
 
+fn lock_counter(counter: &Arc<Mutex<KvCounter>>) -> anyhow::Result<MutexGuard<'_, KvCounter>> {
+    counter
+        .lock()
+        .map_err(|_| anyhow!("failed to acquire lock for counter"))
+}
+
 fn lock_map(
-    map: &Arc<Mutex<HashMap<String, usize>>>,
-) -> anyhow::Result<MutexGuard<HashMap<String, usize>>> {
+    map: &Arc<Mutex<HashMap<String, Totals>>>,
+) -> anyhow::Result<MutexGuard<'_, HashMap<String, Totals>>> {
     map.lock()
         .map_err(|_| anyhow!("failed to acquire lock for map"))
 }
 
 fn lock_serializer<W>(
     serializer: &Arc<Mutex<MessageSerializer<W>>>,
-) -> anyhow::Result<MutexGuard<MessageSerializer<W>>>
+) -> anyhow::Result<MutexGuard<'_, MessageSerializer<W>>>
 where
     W: std::io::Write + Send + Sync,
 {
@@ -192,7 +601,215 @@ where
 }
 
 fn main() -> anyhow::Result<()> {
+    env_logger::builder()
+        .filter_level(LevelFilter::Info)
+        .target(Target::Stderr)
+        .try_init()
+        .context("failed to init logger")?;
     let reader = std::io::stdin().lock();
     let writer = std::io::stdout();
     run_node::<CounterNode<_>, _, _, _>(reader, writer)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use maelstrom::simulation::{step, Cluster, SharedWriter};
+    use proptest::prelude::*;
+    use proptest::sample::Index;
+    use serde_json::json;
+
+    type CounterCluster = Cluster<CounterNode<SharedWriter>, InPayload>;
+
+    /// adds a delta through a node
+    fn add() -> impl Strategy<Value = (Index, i64)> {
+        (any::<Index>(), -100..100i64)
+    }
+
+    #[test]
+    fn totals_merge_by_maximum() {
+        let mut totals = Totals::default();
+        totals.add(5);
+        totals.add(-7);
+        let older = totals;
+        totals.add(-1);
+        assert_eq!(totals.value(), -3);
+
+        let mut merged = Totals::default();
+        merged.merge(totals);
+        merged.merge(older);
+        assert_eq!(merged, totals);
+    }
+
+    #[test]
+    fn stray_store_replies_are_ignored() {
+        let mut cluster = CounterCluster::new(1).unwrap();
+        let body = json!({ "type": "cas_ok", "in_reply_to": 7 });
+        cluster.request("seq-kv", "n0", body).unwrap();
+        cluster
+            .request("c0", "n0", json!({ "type": "add", "delta": 3 }))
+            .unwrap();
+        assert_eq!(read_all(&mut cluster).unwrap(), vec![3]);
+        cluster.shutdown().unwrap();
+    }
+
+    fn seq_kv_cluster(node_count: usize) -> CounterCluster {
+        CounterCluster::with_factory(node_count, |node_id, neighbors, serializer| {
+            Ok(CounterNode::with_store(
+                node_id,
+                neighbors,
+                serializer,
+                CounterStore::SeqKv,
+            ))
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn seq_kv_adds_survive_contention() {
+        for stale_reads in [false, true] {
+            let mut cluster = seq_kv_cluster(3);
+            if stale_reads {
+                cluster.serve_stale_reads();
+            }
+            let node_ids = cluster.node_ids().map(String::from).collect::<Vec<_>>();
+            let mut total = 0;
+            for delta in -5..15 {
+                for node_id in &node_ids {
+                    cluster
+                        .request("c0", node_id, json!({ "type": "add", "delta": delta }))
+                        .unwrap();
+                    total += delta;
+                }
+                // delivering the newest messages first makes nodes race for the same value
+                while cluster.in_flight() > 0 {
+                    cluster.deliver(cluster.in_flight() - 1).unwrap();
+                }
+            }
+            let acked = cluster
+                .wait_for_replies(60, Duration::from_secs(5))
+                .unwrap();
+            assert!(acked.iter().all(|reply| reply["body"]["type"] == "add_ok"));
+
+            for node_id in &node_ids {
+                cluster
+                    .request("c0", node_id, json!({ "type": "read" }))
+                    .unwrap();
+            }
+            let values = cluster
+                .wait_for_replies(node_ids.len(), Duration::from_secs(5))
+                .unwrap()
+                .iter()
+                .map(|reply| reply["body"]["value"].clone())
+                .collect::<Vec<_>>();
+            assert!(values.iter().all(|value| *value == total), "{values:?}");
+            cluster.shutdown().unwrap();
+        }
+    }
+
+    #[test]
+    fn seq_kv_reads_see_earlier_adds() {
+        let mut cluster = seq_kv_cluster(2);
+        cluster.serve_stale_reads();
+        cluster
+            .request("c0", "n0", json!({ "type": "add", "delta": 5 }))
+            .unwrap();
+        cluster.wait_for_replies(1, Duration::from_secs(5)).unwrap();
+
+        // n1 never wrote the counter, so without its sentinel it would read it from before the add
+        cluster
+            .request("c1", "n1", json!({ "type": "read" }))
+            .unwrap();
+        let reply = &cluster.wait_for_replies(1, Duration::from_secs(5)).unwrap()[0];
+        assert_eq!(reply["body"]["value"], 5);
+        cluster.shutdown().unwrap();
+    }
+
+    #[test]
+    fn seq_kv_reads_are_not_starved_by_adds() {
+        let mut cluster = seq_kv_cluster(1);
+        for delta in 0..3 {
+            cluster
+                .request("c0", "n0", json!({ "type": "add", "delta": delta }))
+                .unwrap();
+        }
+        cluster
+            .request("c1", "n0", json!({ "type": "read" }))
+            .unwrap();
+        // another add arrives while each request to seq-kv is in flight
+        for delta in 3..6 {
+            cluster.deliver(0).unwrap();
+            cluster.deliver(0).unwrap();
+            cluster
+                .request("c0", "n0", json!({ "type": "add", "delta": delta }))
+                .unwrap();
+        }
+        let replies = cluster.take_replies().unwrap();
+        let read = replies
+            .iter()
+            .position(|reply| reply["body"]["type"] == "read_ok");
+        assert!(read.is_some(), "{replies:?}");
+        cluster.shutdown().unwrap();
+    }
+
+    #[test]
+    fn seq_kv_rejects_adds_that_overflow() {
+        let mut cluster = seq_kv_cluster(1);
+        for (delta, reply_type) in [(i64::MAX, "add_ok"), (1, "error")] {
+            cluster
+                .request("c0", "n0", json!({ "type": "add", "delta": delta }))
+                .unwrap();
+            let reply = &cluster.wait_for_replies(1, Duration::from_secs(5)).unwrap()[0];
+            assert_eq!(reply["body"]["type"], reply_type);
+        }
+        cluster
+            .request("c0", "n0", json!({ "type": "read" }))
+            .unwrap();
+        let reply = &cluster.wait_for_replies(1, Duration::from_secs(5)).unwrap()[0];
+        assert_eq!(reply["body"]["value"], i64::MAX);
+        cluster.shutdown().unwrap();
+    }
+
+    fn read_all(cluster: &mut CounterCluster) -> anyhow::Result<Vec<i64>> {
+        cluster.take_replies()?;
+        let node_ids = cluster.node_ids().map(String::from).collect::<Vec<_>>();
+        for node_id in &node_ids {
+            cluster.request("c0", node_id, json!({ "type": "read" }))?;
+        }
+        Ok(cluster
+            .take_replies()?
+            .iter()
+            .map(|reply| reply["body"]["value"].as_i64().unwrap())
+            .collect())
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(16))]
+        #[test]
+        fn counter_sums_converge(
+            node_count in 1..5usize,
+            steps in proptest::collection::vec(step(add()), 0..40),
+        ) {
+            let mut cluster = CounterCluster::new(node_count).unwrap();
+            let node_ids = cluster.node_ids().map(String::from).collect::<Vec<_>>();
+
+            let mut total = 0;
+            for step in steps {
+                if let Some((node, delta)) = cluster.apply(step).unwrap() {
+                    let body = json!({ "type": "add", "delta": delta });
+                    cluster.request("c0", &node_ids[node.index(node_count)], body).unwrap();
+                    total += delta;
+                }
+            }
+
+            let converged = cluster
+                .run_until(Duration::from_secs(5), |cluster| {
+                    Ok(read_all(cluster)?.iter().all(|&value| value == total))
+                })
+                .unwrap();
+            prop_assert!(converged, "nodes did not converge to {total}");
+            cluster.shutdown().unwrap();
+        }
+    }
+}
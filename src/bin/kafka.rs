@@ -92,7 +92,7 @@ impl LogManager {
         log.send(item)
     }
 
-    fn poll(&self, key: &str, offset: usize) -> SerializableIterator<[usize; 2]> {
+    fn poll(&self, key: &str, offset: usize) -> SerializableIterator<'_, [usize; 2]> {
         match self.map.get(key) {
             Some(log) => SerializableIterator::new(log.poll(offset)),
             None => SerializableIterator::new(std::iter::empty()),
@@ -175,19 +175,23 @@ impl<W> Node<W, InPayload> for KafkaNode<W>
 where
     W: std::io::Write + Send + Sync + 'static,
 {
-    fn new(node_id: String, mut neighbors: Vec<String>, serializer: MessageSerializer<W>) -> Self {
+    fn new(
+        node_id: String,
+        mut neighbors: Vec<String>,
+        serializer: MessageSerializer<W>,
+    ) -> anyhow::Result<Self> {
         neighbors.sort();
         let (role, leader_id) = match neighbors.first() {
             Some(id) if id < &node_id => (Role::Follower, id.clone()),
             _ => (Role::Leader, node_id.clone()),
         };
-        Self {
+        Ok(Self {
             serializer: serializer.into(),
             log_manager: LogManager::new(),
             role,
             leader_id,
             node_id,
-        }
+        })
     }
 
     fn process(&mut self, in_msg: maelstrom::InMessage<InPayload>) -> anyhow::Result<()>
@@ -427,7 +431,7 @@ where
     fn poll(
         &self,
         offsets: HashMap<String, usize>,
-    ) -> HashMap<String, SerializableIterator<[usize; 2]>> {
+    ) -> HashMap<String, SerializableIterator<'_, [usize; 2]>> {
         offsets
             .into_iter()
             .map(|(key, offset)| {
@@ -464,3 +468,94 @@ fn main() -> anyhow::Result<()> {
     let writer = std::io::stdout();
     maelstrom::run_node::<KafkaNode<_>, _, _, _>(reader, writer)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use maelstrom::simulation::{lossless_step, Cluster, SharedWriter};
+    use proptest::prelude::*;
+    use proptest::sample::Index;
+    use serde_json::json;
+    use std::time::Duration;
+
+    type KafkaCluster = Cluster<KafkaNode<SharedWriter>, InPayload>;
+
+    const KEYS: [&str; 3] = ["k0", "k1", "k2"];
+
+    /// sends `item` to a key through a node
+    #[derive(Debug, Clone)]
+    struct Send {
+        node: Index,
+        key: Index,
+        item: usize,
+    }
+
+    fn send() -> impl Strategy<Value = Send> {
+        (any::<Index>(), any::<Index>(), 0..1000usize).prop_map(|(node, key, item)| Send {
+            node,
+            key,
+            item,
+        })
+    }
+
+    /// records acknowledged sends, checking offsets of each key only ever increase
+    fn record_send_oks(
+        cluster: &mut KafkaCluster,
+        sent: &HashMap<usize, (&'static str, usize)>,
+        acked: &mut HashMap<&'static str, Vec<[usize; 2]>>,
+    ) -> Result<(), TestCaseError> {
+        for reply in cluster.take_replies().unwrap() {
+            let in_reply_to = reply["body"]["in_reply_to"].as_u64().unwrap() as usize;
+            let offset = reply["body"]["offset"].as_u64().unwrap() as usize;
+            let (key, item) = sent[&in_reply_to];
+            let log = acked.entry(key).or_default();
+            if let Some([last, _]) = log.last() {
+                prop_assert!(offset > *last, "{key} offset {offset} after {last}");
+            }
+            log.push([offset, item]);
+        }
+        Ok(())
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+        #[test]
+        fn offsets_are_monotonic(
+            node_count in 1..4usize,
+            steps in proptest::collection::vec(lossless_step(send()), 0..60),
+        ) {
+            let mut cluster = KafkaCluster::new(node_count).unwrap();
+            let node_ids = cluster.node_ids().map(String::from).collect::<Vec<_>>();
+
+            let mut sent = HashMap::new();
+            let mut acked = HashMap::new();
+            for step in steps {
+                if let Some(Send { node, key, item }) = cluster.apply(step).unwrap() {
+                    let key = KEYS[key.index(KEYS.len())];
+                    let body = json!({ "type": "send", "key": key, "msg": item });
+                    let node_id = &node_ids[node.index(node_count)];
+                    let msg_id = cluster.request("c0", node_id, body).unwrap();
+                    sent.insert(msg_id, (key, item));
+                }
+                record_send_oks(&mut cluster, &sent, &mut acked)?;
+            }
+            cluster.run_for(Duration::from_millis(5)).unwrap();
+            record_send_oks(&mut cluster, &sent, &mut acked)?;
+
+            let offsets = KEYS.iter().map(|key| (*key, 0)).collect::<HashMap<_, _>>();
+            let body = json!({ "type": "poll", "offsets": offsets });
+            cluster.request("c0", &node_ids[0], body).unwrap();
+            let replies = cluster.take_replies().unwrap();
+            let polled: HashMap<String, Vec<[usize; 2]>> =
+                serde_json::from_value(replies[0]["body"]["msgs"].clone()).unwrap();
+            for key in KEYS {
+                let log = polled.get(key).cloned().unwrap_or_default();
+                prop_assert!(log.windows(2).all(|pair| pair[0][0] < pair[1][0]));
+                let expected = acked.get(key).cloned().unwrap_or_default();
+                prop_assert_eq!(log, expected);
+            }
+            cluster.shutdown().unwrap();
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use std::io::{BufRead, BufReader, Write};
+use std::process::{ChildStdin, Command, Stdio};
+use std::thread;
+
+use anyhow::{anyhow, bail, Context};
+use serde_json::{json, Map, Value};
+
+const CLIENT_ID: &str = "c0";
+const NODE_ID: &str = "n0";
+/// the Maelstrom services a node may send requests to
+const SERVICES: [&str; 3] = ["lin-kv", "seq-kv", "lww-kv"];
+
+const USAGE: &str = "usage: repl [--node-count N] <node-binary> [args...]
+
+Spawns the node binary as n0 and performs init with node ids n0..n(N-1).
+Each input line is sent to the node as one message, and is either:
+  <type> [field=value ...]    e.g. `send key=k1 msg=5`, values parsed as JSON when possible
+  {\"type\": ...}               a raw JSON body
+  {\"src\": ..., \"body\": ...}   a raw JSON envelope, e.g. to impersonate another node
+Missing src, dest and msg_id are filled in.";
+
+struct Client {
+    stdin: ChildStdin,
+    msg_id: usize,
+}
+
+impl Client {
+    fn send(&mut self, mut msg: Value) -> anyhow::Result<()> {
+        let envelope = msg
+            .as_object_mut()
+            .ok_or_else(|| anyhow!("message must be a JSON object"))?;
+        envelope.entry("src").or_insert_with(|| json!(CLIENT_ID));
+        envelope.entry("dest").or_insert_with(|| json!(NODE_ID));
+        let body = envelope
+            .get_mut("body")
+            .and_then(Value::as_object_mut)
+            .ok_or_else(|| anyhow!("message body must be a JSON object"))?;
+        if !body.contains_key("type") {
+            bail!("message body has no type");
+        }
+        body.entry("msg_id").or_insert_with(|| json!(self.msg_id));
+        self.msg_id += 1;
+
+        serde_json::to_writer(&mut self.stdin, &msg).context("failed to serialize message")?;
+        self.stdin
+            .write_all(b"\n")
+            .and_then(|_| self.stdin.flush())
+            .context("failed to write message to node")
+    }
+}
+
+/// Turns an input line into a message envelope, which may still lack src, dest and msg_id. Only
+/// the spawned node reads the messages, so a raw envelope cannot be addressed to anyone else.
+fn parse_line(line: &str) -> anyhow::Result<Value> {
+    if line.starts_with('{') {
+        let value: Value = serde_json::from_str(line).context("failed to parse raw JSON")?;
+        if value.get("body").is_none() {
+            return Ok(json!({ "body": value }));
+        }
+        match value.get("dest") {
+            Some(dest) if dest != NODE_ID => bail!("unknown target {dest}, only {NODE_ID} runs"),
+            _ => return Ok(value),
+        }
+    }
+
+    let mut words = line.split_whitespace();
+    let msg_type = words.next().ok_or_else(|| anyhow!("empty message"))?;
+    let mut body = Map::new();
+    body.insert("type".to_string(), json!(msg_type));
+    for word in words {
+        let (field, value) = word
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected field=value, got {word:?}"))?;
+        let value = serde_json::from_str(value).unwrap_or_else(|_| json!(value));
+        body.insert(field.to_string(), value);
+    }
+    Ok(json!({ "body": body }))
+}
+
+/// formats a line the node wrote, either a reply to a client or a send to another node or service
+fn format_output(line: &str, node_ids: &[String]) -> String {
+    let Ok(msg) = serde_json::from_str::<Value>(line) else {
+        return format!("<- (unparseable) {line}");
+    };
+    let dest = msg.get("dest").and_then(Value::as_str).unwrap_or_default();
+    let arrow = if node_ids.iter().any(|id| id == dest) || SERVICES.contains(&dest) {
+        format!("-> {dest}")
+    } else {
+        "<-".to_string()
+    };
+    let pretty = serde_json::to_string_pretty(&msg).unwrap_or_else(|_| line.to_string());
+    format!("{arrow} {pretty}")
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1).peekable();
+    let mut node_count = 1;
+    if args.peek().map(String::as_str) == Some("--node-count") {
+        args.next();
+        node_count = args
+            .next()
+            .ok_or_else(|| anyhow!("--node-count requires a value\n\n{USAGE}"))?
+            .parse::<usize>()
+            .context("failed to parse --node-count")?;
+    }
+    let binary = args.next().ok_or_else(|| anyhow!(USAGE))?;
+
+    let mut child = Command::new(&binary)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .with_context(|| format!("failed to spawn {binary:?}"))?;
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow!("failed to capture node stdout"))?;
+    let stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("failed to capture node stdin"))?;
+
+    let node_ids = (0..node_count.max(1))
+        .map(|idx| format!("n{idx}"))
+        .collect::<Vec<_>>();
+    let printer = {
+        let node_ids = node_ids.clone();
+        thread::spawn(move || -> anyhow::Result<()> {
+            for line in BufReader::new(stdout).lines() {
+                let line = line.context("failed to read line from node")?;
+                println!("{}", format_output(&line, &node_ids));
+            }
+            Ok(())
+        })
+    };
+
+    let mut client = Client { stdin, msg_id: 1 };
+    client
+        .send(json!({ "body": { "type": "init", "node_id": NODE_ID, "node_ids": node_ids } }))
+        .context("failed to send init message")?;
+
+    for line in std::io::stdin().lock().lines() {
+        let line = line.context("failed to read line from stdin")?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Err(err) = parse_line(line).and_then(|msg| client.send(msg)) {
+            eprintln!("error: {err:#}");
+        }
+    }
+
+    drop(client);
+    child.wait().context("failed to wait for node to exit")?;
+    printer
+        .join()
+        .map_err(|_| anyhow!("failed to join printer thread"))?
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_fields_and_raw_json() {
+        assert_eq!(
+            parse_line("send key=k1 msg=5 tag=x").unwrap(),
+            json!({ "body": { "type": "send", "key": "k1", "msg": 5, "tag": "x" } })
+        );
+        assert_eq!(
+            parse_line(r#"{"type": "read"}"#).unwrap(),
+            json!({ "body": { "type": "read" } })
+        );
+        let envelope = json!({ "src": "n1", "dest": "n0", "body": { "type": "gossip" } });
+        assert_eq!(parse_line(&envelope.to_string()).unwrap(), envelope);
+    }
+
+    #[test]
+    fn reject_bad_lines() {
+        assert!(parse_line(r#"{"type": "read""#).is_err());
+        assert!(parse_line("send key").is_err());
+        let envelope = json!({ "dest": "n1", "body": { "type": "read" } });
+        assert!(parse_line(&envelope.to_string()).is_err());
+    }
+
+    #[test]
+    fn sends_to_services_are_not_replies() {
+        let node_ids = vec!["n0".to_string(), "n1".to_string()];
+        let format = |dest: &str| {
+            let line = json!({ "src": "n0", "dest": dest, "body": {} }).to_string();
+            format_output(&line, &node_ids)
+        };
+        assert!(format("n1").starts_with("-> n1"));
+        assert!(format("lin-kv").starts_with("-> lin-kv"));
+        assert!(format("c0").starts_with("<-"));
+        assert!(format("n2").starts_with("<-"));
+    }
+}
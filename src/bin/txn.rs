@@ -97,11 +97,15 @@ impl<W> Node<W, InPayload> for TxnNode<W>
 where
     W: std::io::Write + Send + Sync + 'static,
 {
-    fn new(_node_id: String, _node_ids: Vec<String>, serializer: MessageSerializer<W>) -> Self {
-        Self {
+    fn new(
+        _node_id: String,
+        _node_ids: Vec<String>,
+        serializer: MessageSerializer<W>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
             serializer,
             store: KVStore::new(),
-        }
+        })
     }
 
     fn process(&mut self, in_msg: InMessage<InPayload>) -> anyhow::Result<()>
@@ -138,6 +142,11 @@ fn main() -> anyhow::Result<()> {
 mod test {
     use super::*;
 
+    use maelstrom::simulation::{step, Cluster, SharedWriter};
+    use proptest::prelude::*;
+    use proptest::sample::Index;
+    use serde_json::json;
+
     #[test]
     fn deserialize_transaction() {
         assert_eq!(
@@ -189,4 +198,62 @@ mod test {
             r#"["w",6,5]"#.to_string()
         );
     }
+
+    type TxnCluster = Cluster<TxnNode<SharedWriter>, InPayload>;
+
+    fn micro_op() -> impl Strategy<Value = (bool, usize)> {
+        (any::<bool>(), 0..4usize)
+    }
+
+    /// a transaction sent to a node
+    fn txn() -> impl Strategy<Value = (Index, Vec<(bool, usize)>)> {
+        (any::<Index>(), proptest::collection::vec(micro_op(), 1..6))
+    }
+
+    proptest! {
+        #[test]
+        fn read_your_writes(
+            node_count in 1..4usize,
+            steps in proptest::collection::vec(step(txn()), 0..40),
+        ) {
+            let mut cluster = TxnCluster::new(node_count).unwrap();
+            let node_ids = cluster.node_ids().map(String::from).collect::<Vec<_>>();
+
+            // one client per node, each only ever talking to its own node
+            let mut writes: Vec<HashMap<usize, usize>> = vec![HashMap::new(); node_count];
+            let mut next_value = 0;
+            for step in steps {
+                let Some((node, micro_ops)) = cluster.apply(step).unwrap() else {
+                    continue;
+                };
+                let node = node.index(node_count);
+                let txn = micro_ops
+                    .into_iter()
+                    .map(|(is_write, key)| {
+                        next_value += 1;
+                        match is_write {
+                            true => Transaction::Write { key, value: next_value },
+                            false => Transaction::Read { key, value: None },
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                let body = json!({ "type": "txn", "txn": txn });
+                cluster.request(&format!("c{node}"), &node_ids[node], body).unwrap();
+                let replies = cluster.take_replies().unwrap();
+                let result: Vec<Transaction> =
+                    serde_json::from_value(replies[0]["body"]["txn"].clone()).unwrap();
+                for transaction in result {
+                    match transaction {
+                        Transaction::Write { key, value } => {
+                            writes[node].insert(key, value);
+                        }
+                        Transaction::Read { key, value } => {
+                            prop_assert_eq!(value, writes[node].get(&key).copied());
+                        }
+                    }
+                }
+            }
+            cluster.shutdown().unwrap();
+        }
+    }
 }
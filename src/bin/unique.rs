@@ -1,62 +1,849 @@
-use anyhow::Context;
-use maelstrom::{run_node, DeconstructedInMessage, InMessage, MessageSerializer, Node};
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context};
+use env_logger::Target;
+use log::LevelFilter;
+use maelstrom::{
+    env_config, expire_store_requests, mix, run_node, DeconstructedInMessage, ErrorCode, InMessage,
+    MessageSerializer, Node, PartialInMessage, StoreClient,
+};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
 
 #[derive(Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 enum InPayload {
-    Generate,
+    /// `count` is an extension for fetching a batch of ids in one round trip
+    Generate {
+        count: Option<usize>,
+    },
+    /// extension for debugging, splits an id back into its components
+    Decode {
+        id: u64,
+    },
+    ReadOk {
+        value: u64,
+    },
+    CasOk,
+    Error {
+        code: ErrorCode,
+        text: String,
+    },
 }
 
-#[derive(Copy, Clone, Serialize)]
+#[derive(Clone, Serialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
-enum OutPayload {
-    GenerateOk { id: u64 },
+enum OutPayload<'a> {
+    GenerateOk {
+        id: Id,
+    },
+    #[serde(rename = "generate_ok")]
+    GenerateBatchOk {
+        ids: &'a [Id],
+    },
+    DecodeOk {
+        unix_millis: u64,
+        node_index: u64,
+        sequence: u64,
+    },
+    Error {
+        code: ErrorCode,
+        text: &'a str,
+    },
+    Read {
+        key: &'a str,
+    },
+    Cas {
+        key: &'a str,
+        from: u64,
+        to: u64,
+        create_if_not_exists: bool,
+    },
+}
+
+/// Where ids come from, set through the `UNIQUE_ID_SOURCE` environment variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum IdSource {
+    /// k-sortable ids generated locally, see [`Snowflake`]
+    #[default]
+    Snowflake,
+    /// dense ids handed out from blocks reserved in lin-kv, see [`BlockAllocator`]
+    LinKv,
+}
+
+impl FromStr for IdSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "snowflake" => Ok(Self::Snowflake),
+            "lin-kv" => Ok(Self::LinKv),
+            _ => bail!("expected snowflake or lin-kv"),
+        }
+    }
+}
+
+/// How ids are sent to clients, set through the `UNIQUE_ID_FORMAT` environment variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum IdFormat {
+    #[default]
+    Numeric,
+    UuidV4,
+    UuidV7,
+    Ulid,
+}
+
+impl FromStr for IdFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "numeric" => Ok(Self::Numeric),
+            "uuidv4" => Ok(Self::UuidV4),
+            "uuidv7" => Ok(Self::UuidV7),
+            "ulid" => Ok(Self::Ulid),
+            _ => bail!("expected numeric, uuidv4, uuidv7 or ulid"),
+        }
+    }
+}
+
+impl IdFormat {
+    const ULID_ALPHABET: &'static [u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+    /// Lays out the components of `snowflake` in this format. Random bits only fill the space
+    /// left over, so ids stay unique across nodes no matter what the random number generator
+    /// returns.
+    fn format<R>(self, snowflake: Snowflake, rng: &mut R) -> Id
+    where
+        R: Rng,
+    {
+        let unix_millis = (snowflake.timestamp + Snowflake::EPOCH) as u128;
+        let node_index = snowflake.node_index as u128;
+        let sequence = snowflake.sequence as u128;
+        match self {
+            Self::Numeric => Id::Number(snowflake.encode()),
+            Self::UuidV4 => {
+                let random = rng.gen::<u64>() as u128 & ((1 << 58) - 1);
+                Id::Text(uuid(4, (mix(snowflake.encode()) as u128) << 58 | random))
+            }
+            Self::UuidV7 => {
+                // the sequence sits right below the timestamp as a monotonic counter, RFC 9562
+                // section 6.2 method 1
+                let random = rng.gen::<u64>() as u128 & ((1 << 52) - 1);
+                let bits = unix_millis << 74 | sequence << 62 | node_index << 52 | random;
+                Id::Text(uuid(7, bits))
+            }
+            Self::Ulid => {
+                let random = rng.gen::<u64>() as u128 & ((1 << 58) - 1);
+                let bits = unix_millis << 80 | node_index << 70 | sequence << 58 | random;
+                let ulid = (0..26)
+                    .map(|idx| Self::ULID_ALPHABET[(bits >> (125 - 5 * idx)) as usize & 31] as char)
+                    .collect();
+                Id::Text(ulid)
+            }
+        }
+    }
+}
+
+/// An id as sent to clients, a number in the numeric format and a string otherwise.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(untagged)]
+enum Id {
+    Number(u64),
+    Text(String),
+}
+
+/// formats the 122 bits of `payload` as a uuid, around the version and variant fields
+fn uuid(version: u128, payload: u128) -> String {
+    let bits = (payload >> 74) << 80
+        | version << 76
+        | ((payload >> 62) & 0xfff) << 64
+        | 0b10 << 62
+        | (payload & ((1 << 62) - 1));
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        bits >> 96,
+        (bits >> 80) & 0xffff,
+        (bits >> 64) & 0xffff,
+        (bits >> 48) & 0xffff,
+        bits & 0xffff_ffff_ffff
+    )
+}
+
+/// Components of a k-sortable id: 41 bits of milliseconds since [`Snowflake::EPOCH`], 10 bits
+/// of node index and 12 bits of per-millisecond sequence, from most to least significant.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct Snowflake {
+    timestamp: u64,
+    node_index: u64,
+    sequence: u64,
+}
+
+impl Snowflake {
+    /// 2023-01-01T00:00:00Z in milliseconds since the unix epoch
+    const EPOCH: u64 = 1_672_531_200_000;
+    const NODE_BITS: u32 = 10;
+    const SEQUENCE_BITS: u32 = 12;
+    const MAX_NODE_INDEX: u64 = (1 << Self::NODE_BITS) - 1;
+    const MAX_SEQUENCE: u64 = (1 << Self::SEQUENCE_BITS) - 1;
+
+    fn encode(self) -> u64 {
+        (self.timestamp << (Self::NODE_BITS + Self::SEQUENCE_BITS))
+            | (self.node_index << Self::SEQUENCE_BITS)
+            | self.sequence
+    }
+
+    fn decode(id: u64) -> Self {
+        Self {
+            timestamp: id >> (Self::NODE_BITS + Self::SEQUENCE_BITS),
+            node_index: (id >> Self::SEQUENCE_BITS) & Self::MAX_NODE_INDEX,
+            sequence: id & Self::MAX_SEQUENCE,
+        }
+    }
+}
+
+struct SnowflakeGenerator {
+    node_index: u64,
+    last: Snowflake,
+}
+
+impl SnowflakeGenerator {
+    fn new(node_index: u64) -> Self {
+        Self {
+            node_index,
+            last: Snowflake {
+                timestamp: 0,
+                node_index,
+                sequence: 0,
+            },
+        }
+    }
+
+    fn next(&mut self) -> anyhow::Result<u64> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("system clock is before the unix epoch")?
+            .as_millis() as u64;
+        self.next_at(now.saturating_sub(Snowflake::EPOCH))
+    }
+
+    /// ids of a batch are taken from consecutive sequence numbers, so they are contiguous unless
+    /// the batch crosses into the next millisecond
+    fn next_batch(&mut self, count: usize) -> anyhow::Result<Vec<u64>> {
+        (0..count).map(|_| self.next()).collect()
+    }
+
+    /// Ids never repeat and never decrease: if the clock moved backwards the last timestamp is
+    /// reused, and once its sequence is exhausted the timestamp is advanced ahead of the clock.
+    fn next_at(&mut self, timestamp: u64) -> anyhow::Result<u64> {
+        if self.node_index > Snowflake::MAX_NODE_INDEX {
+            bail!(
+                "node index {} does not fit in {} bits",
+                self.node_index,
+                Snowflake::NODE_BITS
+            );
+        }
+        if timestamp < self.last.timestamp {
+            log::warn!(
+                "clock moved backwards by {}ms",
+                self.last.timestamp - timestamp
+            );
+        }
+        self.last = if timestamp > self.last.timestamp {
+            Snowflake {
+                timestamp,
+                node_index: self.node_index,
+                sequence: 0,
+            }
+        } else if self.last.sequence < Snowflake::MAX_SEQUENCE {
+            Snowflake {
+                sequence: self.last.sequence + 1,
+                ..self.last
+            }
+        } else {
+            Snowflake {
+                timestamp: self.last.timestamp + 1,
+                node_index: self.node_index,
+                sequence: 0,
+            }
+        };
+        Ok(self.last.encode())
+    }
+}
+
+/// Hands out ids from blocks reserved in lin-kv. The store holds the first id that was never
+/// reserved and only moves forward through `cas`, so a block is never given to two nodes, nor
+/// twice to the same node after a restart. A reservation whose reply got lost is skipped.
+struct BlockAllocator {
+    blocks: VecDeque<Range<u64>>,
+    /// generate requests in arrival order, with their batch size
+    waiting: VecDeque<(PartialInMessage, Option<usize>)>,
+    /// the `purpose` of a request is the block a `cas` reserves, `None` for a `read`
+    store: StoreClient<Option<Range<u64>>>,
+    /// our best guess of the value in the store, the `from` of the next reservation
+    last_seen: u64,
+}
+
+impl BlockAllocator {
+    const SERVICE: &'static str = "lin-kv";
+    const KEY: &'static str = "unique-ids";
+    const BLOCK_SIZE: u64 = 1000;
+    /// a new block is reserved once fewer ids than this are left
+    const LOW_WATER: u64 = 250;
+    const STORE_TIMEOUT: Duration = Duration::from_millis(500);
+
+    fn new(node_id: String) -> Self {
+        Self {
+            store: StoreClient::new(node_id, Self::SERVICE, Self::STORE_TIMEOUT),
+            blocks: VecDeque::new(),
+            waiting: VecDeque::new(),
+            last_seen: 0,
+        }
+    }
+
+    fn available(&self) -> u64 {
+        self.blocks
+            .iter()
+            .map(|block| block.end - block.start)
+            .sum()
+    }
+
+    fn demand(&self) -> u64 {
+        self.waiting
+            .iter()
+            .map(|(_, count)| count.unwrap_or(1) as u64)
+            .sum()
+    }
+
+    fn take(&mut self, count: u64) -> Vec<u64> {
+        let mut ids = Vec::with_capacity(count as usize);
+        while (ids.len() as u64) < count {
+            let Some(block) = self.blocks.front_mut() else {
+                break;
+            };
+            let end = block.end.min(block.start + count - ids.len() as u64);
+            ids.extend(block.start..end);
+            block.start = end;
+            if block.is_empty() {
+                self.blocks.pop_front();
+            }
+        }
+        ids
+    }
+
+    /// answers waiting requests in order for as long as there are enough ids
+    fn serve<W>(&mut self, serializer: &mut MessageSerializer<W>) -> anyhow::Result<()>
+    where
+        W: std::io::Write + Send + Sync,
+    {
+        while let Some((_, count)) = self.waiting.front() {
+            if self.available() < count.unwrap_or(1) as u64 {
+                break;
+            }
+            let (partial_in_msg, count) =
+                self.waiting.pop_front().unwrap_or_else(|| unreachable!());
+            let ids = self.take(count.unwrap_or(1) as u64);
+            let payload = match count {
+                None => OutPayload::GenerateOk {
+                    id: Id::Number(ids[0]),
+                },
+                Some(_) => OutPayload::GenerateBatchOk {
+                    ids: &ids.into_iter().map(Id::Number).collect::<Vec<_>>(),
+                },
+            };
+            let mut out_msg = partial_in_msg.to_out_msg(payload);
+            serializer
+                .send(&mut out_msg)
+                .context("failed to serialize generate_ok message")?;
+        }
+        Ok(())
+    }
+
+    /// reserves another block if none is being reserved and the remaining ids run low
+    fn refill<W>(&mut self, serializer: &mut MessageSerializer<W>) -> anyhow::Result<()>
+    where
+        W: std::io::Write + Send + Sync,
+    {
+        let shortfall = (self.demand() + Self::LOW_WATER).saturating_sub(self.available());
+        if self.store.is_busy() || shortfall == 0 {
+            return Ok(());
+        }
+        let from = self.last_seen;
+        let to = from + shortfall.max(Self::BLOCK_SIZE);
+        let payload = OutPayload::Cas {
+            key: Self::KEY,
+            from,
+            to,
+            create_if_not_exists: from == 0,
+        };
+        self.store.send(serializer, payload, Some(from..to))
+    }
+
+    fn handle_reply<W>(
+        &mut self,
+        in_reply_to: Option<usize>,
+        payload: InPayload,
+        serializer: &mut MessageSerializer<W>,
+    ) -> anyhow::Result<()>
+    where
+        W: std::io::Write + Send + Sync,
+    {
+        // replies to requests that already timed out are stale
+        let Some(request) = self.store.take_reply(in_reply_to) else {
+            return Ok(());
+        };
+        match payload {
+            InPayload::CasOk => {
+                let block = request
+                    .purpose
+                    .ok_or_else(|| anyhow!("received cas_ok in reply to a read"))?;
+                self.last_seen = block.end;
+                self.blocks.push_back(block);
+                self.serve(serializer)?;
+            }
+            InPayload::ReadOk { value } => self.last_seen = value,
+            InPayload::Error {
+                code: ErrorCode::PreconditionFailed,
+                ..
+            } => {
+                let payload = OutPayload::Read { key: Self::KEY };
+                return self.store.send(serializer, payload, None);
+            }
+            InPayload::Error {
+                code: ErrorCode::KeyDoesNotExist,
+                ..
+            } => self.last_seen = 0,
+            InPayload::Error { code, text } => {
+                log::warn!("lin-kv failed with {code:?}: {text}");
+                return self.fail_waiting(serializer);
+            }
+            _ => bail!("unexpected reply from lin-kv"),
+        }
+        self.refill(serializer)
+    }
+
+    /// gives up on a request to the store that went unanswered for too long
+    fn expire<W>(&mut self, serializer: &mut MessageSerializer<W>) -> anyhow::Result<()>
+    where
+        W: std::io::Write + Send + Sync,
+    {
+        match self.store.take_expired() {
+            Some(_) => self.fail_waiting(serializer),
+            None => Ok(()),
+        }
+    }
+
+    fn fail_waiting<W>(&mut self, serializer: &mut MessageSerializer<W>) -> anyhow::Result<()>
+    where
+        W: std::io::Write + Send + Sync,
+    {
+        for (partial_in_msg, _) in self.waiting.drain(..) {
+            let payload = OutPayload::Error {
+                code: ErrorCode::TemporarilyUnavailable,
+                text: "could not reserve ids from lin-kv",
+            };
+            let mut out_msg = partial_in_msg.to_out_msg(payload);
+            serializer
+                .send(&mut out_msg)
+                .context("failed to serialize error message")?;
+        }
+        Ok(())
+    }
+}
+
+enum Generator {
+    Snowflake {
+        generator: SnowflakeGenerator,
+        format: IdFormat,
+    },
+    Blocks {
+        allocator: Arc<Mutex<BlockAllocator>>,
+        handle: JoinHandle<anyhow::Result<()>>,
+        tx: Sender<bool>,
+    },
 }
 
 struct UniqueNode<W>
 where
     W: std::io::Write + Send + Sync + 'static,
 {
-    node_id: String,
-    serializer: MessageSerializer<W>,
+    serializer: Arc<Mutex<MessageSerializer<W>>>,
+    generator: Generator,
 }
 
-impl<W> Node<W, InPayload> for UniqueNode<W>
+impl<W> UniqueNode<W>
 where
     W: std::io::Write + Send + Sync,
 {
-    fn new(node_id: String, _neighbors: Vec<String>, serializer: MessageSerializer<W>) -> Self {
-        Self {
-            node_id,
-            serializer,
+    const MAX_BATCH_SIZE: usize = 10_000;
+    const EXPIRE_SLEEP_TIME: Duration = Duration::from_millis(50);
+
+    fn with_config(
+        node_id: String,
+        neighbors: Vec<String>,
+        serializer: MessageSerializer<W>,
+        source: IdSource,
+        format: IdFormat,
+    ) -> anyhow::Result<Self> {
+        if source == IdSource::LinKv && format != IdFormat::Numeric {
+            bail!("ids from lin-kv can only be numeric, not {format:?}");
         }
+        let serializer = Arc::new(Mutex::new(serializer));
+        let generator = match source {
+            IdSource::Snowflake => {
+                let mut node_ids = neighbors;
+                node_ids.push(node_id.clone());
+                node_ids.sort();
+                let node_index = node_ids
+                    .iter()
+                    .position(|id| id == &node_id)
+                    .unwrap_or_default();
+                Generator::Snowflake {
+                    generator: SnowflakeGenerator::new(node_index as u64),
+                    format,
+                }
+            }
+            IdSource::LinKv => {
+                let allocator = Arc::new(Mutex::new(BlockAllocator::new(node_id)));
+                let (tx, rx) = mpsc::channel();
+                let handle = {
+                    let allocator = Arc::clone(&allocator);
+                    let serializer = Arc::clone(&serializer);
+                    thread::spawn(move || {
+                        expire_store_requests(
+                            allocator,
+                            serializer,
+                            rx,
+                            Self::EXPIRE_SLEEP_TIME,
+                            BlockAllocator::expire,
+                        )
+                    })
+                };
+                Generator::Blocks {
+                    allocator,
+                    handle,
+                    tx,
+                }
+            }
+        };
+        Ok(Self {
+            serializer,
+            generator,
+        })
+    }
+
+    fn lock_serializer(&self) -> anyhow::Result<MutexGuard<'_, MessageSerializer<W>>> {
+        lock_serializer(&self.serializer)
+    }
+}
+
+impl<W> Node<W, InPayload> for UniqueNode<W>
+where
+    W: std::io::Write + Send + Sync,
+{
+    fn new(
+        node_id: String,
+        neighbors: Vec<String>,
+        serializer: MessageSerializer<W>,
+    ) -> anyhow::Result<Self> {
+        let source = env_config("UNIQUE_ID_SOURCE")?.unwrap_or_default();
+        let format = env_config("UNIQUE_ID_FORMAT")?.unwrap_or_default();
+        Self::with_config(node_id, neighbors, serializer, source, format)
     }
 
     fn process(&mut self, in_msg: InMessage<InPayload>) -> anyhow::Result<()> {
-        let mut hasher = DefaultHasher::new();
-        self.node_id.hash(&mut hasher);
-        self.serializer.msg_id().hash(&mut hasher);
-        let id = hasher.finish();
-        let DeconstructedInMessage { partial_in_msg, .. } = in_msg.into();
-        let mut out_msg = partial_in_msg.to_out_msg(OutPayload::GenerateOk { id });
-        self.serializer
+        let in_reply_to = in_msg.body.in_reply_to;
+        let DeconstructedInMessage {
+            partial_in_msg,
+            in_payload,
+        } = in_msg.into();
+        let ids;
+        let text;
+        let payload = match (in_payload, &mut self.generator) {
+            (InPayload::Generate { count: Some(count) }, _) if count > Self::MAX_BATCH_SIZE => {
+                text = format!("cannot generate more than {} ids", Self::MAX_BATCH_SIZE);
+                OutPayload::Error {
+                    code: ErrorCode::MalformedRequest,
+                    text: &text,
+                }
+            }
+            (InPayload::Generate { count: None }, Generator::Snowflake { generator, format }) => {
+                let id = generator.next().context("failed to generate unique id")?;
+                let id = format.format(Snowflake::decode(id), &mut rand::thread_rng());
+                OutPayload::GenerateOk { id }
+            }
+            (
+                InPayload::Generate { count: Some(count) },
+                Generator::Snowflake { generator, format },
+            ) => {
+                let mut rng = rand::thread_rng();
+                ids = generator
+                    .next_batch(count)
+                    .context("failed to generate batch of unique ids")?
+                    .into_iter()
+                    .map(|id| format.format(Snowflake::decode(id), &mut rng))
+                    .collect::<Vec<_>>();
+                OutPayload::GenerateBatchOk { ids: &ids }
+            }
+            (InPayload::Generate { count }, Generator::Blocks { allocator, .. }) => {
+                let mut allocator = lock_allocator(allocator)?;
+                let mut serializer = lock_serializer(&self.serializer)?;
+                allocator.waiting.push_back((partial_in_msg, count));
+                allocator.serve(&mut serializer)?;
+                return allocator.refill(&mut serializer);
+            }
+            (
+                InPayload::Decode { id },
+                Generator::Snowflake {
+                    format: IdFormat::Numeric,
+                    ..
+                },
+            ) => {
+                let snowflake = Snowflake::decode(id);
+                OutPayload::DecodeOk {
+                    unix_millis: snowflake.timestamp + Snowflake::EPOCH,
+                    node_index: snowflake.node_index,
+                    sequence: snowflake.sequence,
+                }
+            }
+            (InPayload::Decode { .. }, Generator::Snowflake { .. }) => OutPayload::Error {
+                code: ErrorCode::NotSupported,
+                text: "only numeric ids can be decoded",
+            },
+            (InPayload::Decode { .. }, Generator::Blocks { .. }) => OutPayload::Error {
+                code: ErrorCode::NotSupported,
+                text: "ids from lin-kv blocks carry no components",
+            },
+            (reply, Generator::Blocks { allocator, .. }) => {
+                let mut allocator = lock_allocator(allocator)?;
+                let mut serializer = lock_serializer(&self.serializer)?;
+                return allocator.handle_reply(in_reply_to, reply, &mut serializer);
+            }
+            (_, Generator::Snowflake { .. }) => {
+                log::warn!("ignoring lin-kv reply to msg {in_reply_to:?} in snowflake mode");
+                return Ok(());
+            }
+        };
+        let mut out_msg = partial_in_msg.to_out_msg(payload);
+        self.lock_serializer()?
             .send(&mut out_msg)
             .context("failed to serialize reply")?;
         Ok(())
     }
 
     fn shutdown(self) -> anyhow::Result<()> {
+        if let Generator::Blocks { handle, tx, .. } = self.generator {
+            tx.send(true)
+                .context("failed to send shutdown signal to expiry thread")?;
+            handle
+                .join()
+                .map_err(|_| anyhow!("failed to join expiry thread"))??;
+        }
         Ok(())
     }
 }
 
+fn lock_allocator(
+    allocator: &Arc<Mutex<BlockAllocator>>,
+) -> anyhow::Result<MutexGuard<'_, BlockAllocator>> {
+    allocator
+        .lock()
+        .map_err(|_| anyhow!("failed to acquire lock for allocator"))
+}
+
+fn lock_serializer<W>(
+    serializer: &Arc<Mutex<MessageSerializer<W>>>,
+) -> anyhow::Result<MutexGuard<'_, MessageSerializer<W>>>
+where
+    W: std::io::Write + Send + Sync,
+{
+    serializer
+        .lock()
+        .map_err(|_| anyhow!("failed to acquire lock for serializer"))
+}
+
 fn main() -> anyhow::Result<()> {
+    env_logger::builder()
+        .filter_level(LevelFilter::Info)
+        .target(Target::Stderr)
+        .try_init()
+        .context("failed to init logger")?;
     let reader = std::io::stdin().lock();
     let writer = std::io::stdout();
     run_node::<UniqueNode<_>, _, _, _>(reader, writer)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use maelstrom::simulation::{Cluster, SharedWriter};
+    use serde_json::json;
+
+    type UniqueCluster = Cluster<UniqueNode<SharedWriter>, InPayload>;
+
+    fn lin_kv_cluster(node_count: usize) -> UniqueCluster {
+        UniqueCluster::with_factory(node_count, |node_id, neighbors, serializer| {
+            UniqueNode::with_config(
+                node_id,
+                neighbors,
+                serializer,
+                IdSource::LinKv,
+                IdFormat::Numeric,
+            )
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn decode_snowflake() {
+        let snowflake = Snowflake {
+            timestamp: 1_234_567,
+            node_index: 17,
+            sequence: 42,
+        };
+        assert_eq!(Snowflake::decode(snowflake.encode()), snowflake);
+    }
+
+    #[test]
+    fn batch_ids_are_distinct_and_increasing() {
+        let mut generator = SnowflakeGenerator::new(5);
+        let ids = generator.next_batch(3000).unwrap();
+        assert_eq!(ids.len(), 3000);
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn oversized_batches_are_rejected() {
+        let mut cluster = UniqueCluster::with_factory(1, |node_id, neighbors, serializer| {
+            UniqueNode::with_config(
+                node_id,
+                neighbors,
+                serializer,
+                IdSource::Snowflake,
+                IdFormat::Numeric,
+            )
+        })
+        .unwrap();
+        let count = UniqueNode::<SharedWriter>::MAX_BATCH_SIZE + 1;
+        let body = json!({ "type": "generate", "count": count });
+        cluster.request("c0", "n0", body).unwrap();
+        let replies = cluster.take_replies().unwrap();
+        assert_eq!(replies[0]["body"]["type"], json!("error"));
+        assert_eq!(replies[0]["body"]["code"], json!(12));
+        cluster.shutdown().unwrap();
+    }
+
+    #[test]
+    fn ids_increase_despite_clock_regression() {
+        let mut generator = SnowflakeGenerator::new(3);
+        let mut ids = vec![generator.next_at(100).unwrap()];
+        ids.push(generator.next_at(100).unwrap());
+        ids.push(generator.next_at(90).unwrap());
+        for _ in 0..=Snowflake::MAX_SEQUENCE {
+            ids.push(generator.next_at(100).unwrap());
+        }
+        ids.push(generator.next_at(101).unwrap());
+
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(
+            Snowflake::decode(ids[2]),
+            Snowflake {
+                timestamp: 100,
+                node_index: 3,
+                sequence: 2,
+            }
+        );
+        assert_eq!(Snowflake::decode(*ids.last().unwrap()).timestamp, 101);
+    }
+
+    #[test]
+    fn blocks_are_not_reused_after_restart() {
+        let mut cluster = lin_kv_cluster(2);
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            for node_id in ["n0", "n1"] {
+                let body = json!({ "type": "generate", "count": 700 });
+                cluster.request("c0", node_id, body).unwrap();
+                let replies = cluster.wait_for_replies(1, Duration::from_secs(2)).unwrap();
+                let batch: Vec<u64> =
+                    serde_json::from_value(replies[0]["body"]["ids"].clone()).unwrap();
+                ids.extend(batch);
+            }
+            cluster.restart("n0").unwrap();
+        }
+        cluster.shutdown().unwrap();
+
+        let count = ids.len();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), count);
+        assert_eq!(count, 4200);
+    }
+
+    #[test]
+    fn unreachable_store_fails_requests() {
+        let mut cluster = lin_kv_cluster(1);
+        cluster
+            .request("c0", "n0", json!({ "type": "generate" }))
+            .unwrap();
+        cluster.drop_message(0);
+        let replies = cluster.wait_for_replies(1, Duration::from_secs(2)).unwrap();
+        assert_eq!(replies[0]["body"]["code"], json!(11));
+
+        cluster
+            .request("c0", "n0", json!({ "type": "generate" }))
+            .unwrap();
+        let replies = cluster.wait_for_replies(1, Duration::from_secs(2)).unwrap();
+        assert_eq!(replies[0]["body"]["id"], json!(0));
+        cluster.shutdown().unwrap();
+    }
+
+    #[test]
+    fn formatted_ids_differ_without_randomness() {
+        let snowflakes = [
+            Snowflake {
+                timestamp: 1_000,
+                node_index: 0,
+                sequence: 7,
+            },
+            Snowflake {
+                timestamp: 1_000,
+                node_index: 1,
+                sequence: 7,
+            },
+        ];
+        for format in [IdFormat::UuidV4, IdFormat::UuidV7, IdFormat::Ulid] {
+            let ids = snowflakes
+                .iter()
+                .map(|&snowflake| {
+                    format.format(snowflake, &mut rand::rngs::mock::StepRng::new(0, 0))
+                })
+                .collect::<Vec<_>>();
+            assert_ne!(ids[0], ids[1], "{format:?}");
+        }
+    }
+
+    #[test]
+    fn uuid_v7_layout() {
+        let snowflake = Snowflake {
+            timestamp: 0,
+            node_index: 5,
+            sequence: 0xabc,
+        };
+        let id = IdFormat::UuidV7.format(snowflake, &mut rand::rngs::mock::StepRng::new(0, 0));
+        // 0x01856aa0c800 is Snowflake::EPOCH, then version 7, the sequence, variant 0b10 and node 5
+        assert_eq!(
+            id,
+            Id::Text("01856aa0-c800-7abc-8050-000000000000".to_string())
+        );
+    }
+}
@@ -0,0 +1,599 @@
+//! Elle-style anomaly detection for rw-register transaction histories.
+//!
+//! A history is a sequence of [`Op`]s whose values are lists of `["r", k, v]`/`["w", k, v]`
+//! micro-ops, the same shape the `txn` workload sends. Every write to a key is expected to carry
+//! a value that is unique for that key, which is what lets us recover which transaction wrote the
+//! value another transaction read.
+//!
+//! From committed transactions we infer a dependency graph:
+//! - `ww`: the writer of a version precedes the writer of the next version of the same key,
+//! - `wr`: the writer of a version precedes every transaction that read it,
+//! - `rw`: a reader of a version precedes the writer of the next version of the same key.
+//!
+//! Version order is derived from the initial `nil` state preceding every write, and from
+//! transactions that read a key and then overwrite it. Cycles in the graph are reported with the
+//! shortest cycle found in each strongly connected component.
+//!
+//! Blind writes, which overwrite a key without reading it first, leave no trace of the version
+//! they replaced, so they get no `ww` edges. Neither process nor real-time order is used to make
+//! up for that, since serializability promises neither, so a G0 cycle is only found when its
+//! transactions read the keys they overwrite.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use serde::Deserialize;
+
+use super::OpKind;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MicroOp {
+    Read { key: usize, value: Option<usize> },
+    Write { key: usize, value: usize },
+}
+
+impl<'a> Deserialize<'a> for MicroOp {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'a>,
+    {
+        #[derive(Deserialize)]
+        struct Data(String, usize, Option<usize>);
+
+        let Data(op, key, value) = Data::deserialize(deserializer)?;
+        match op.as_str() {
+            "r" => Ok(MicroOp::Read { key, value }),
+            "w" => value
+                .ok_or_else(|| serde::de::Error::custom("value is required for write micro-op"))
+                .map(|v| MicroOp::Write { key, value: v }),
+            any => Err(serde::de::Error::unknown_variant(any, &["r", "w"])),
+        }
+    }
+}
+
+/// A single history entry, deserializable from `{"process": 0, "type": "ok", "value": [...]}`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Op {
+    pub process: usize,
+    #[serde(rename = "type")]
+    pub kind: OpKind,
+    #[serde(rename = "value")]
+    pub txn: Vec<MicroOp>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Dependency {
+    Ww,
+    Wr,
+    Rw,
+}
+
+/// A dependency between two operations, identified by their index in the history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub dependency: Dependency,
+    pub key: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AnomalyKind {
+    G0,
+    G1a,
+    G1b,
+    G1c,
+    G2,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Anomaly {
+    /// a cycle made of write-write dependencies only
+    G0 { cycle: Vec<Edge> },
+    /// a committed read of a value written by an aborted transaction
+    G1a {
+        reader: usize,
+        writer: usize,
+        key: usize,
+        value: usize,
+    },
+    /// a committed read of a value that its writer later overwrote in the same transaction
+    G1b {
+        reader: usize,
+        writer: usize,
+        key: usize,
+        value: usize,
+    },
+    /// a cycle of write-write and write-read dependencies with at least one write-read
+    G1c { cycle: Vec<Edge> },
+    /// a cycle with at least one anti-dependency
+    G2 { cycle: Vec<Edge> },
+}
+
+impl Anomaly {
+    pub fn kind(&self) -> AnomalyKind {
+        match self {
+            Anomaly::G0 { .. } => AnomalyKind::G0,
+            Anomaly::G1a { .. } => AnomalyKind::G1a,
+            Anomaly::G1b { .. } => AnomalyKind::G1b,
+            Anomaly::G1c { .. } => AnomalyKind::G1c,
+            Anomaly::G2 { .. } => AnomalyKind::G2,
+        }
+    }
+}
+
+impl fmt::Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Dependency::Ww => write!(f, "ww"),
+            Dependency::Wr => write!(f, "wr"),
+            Dependency::Rw => write!(f, "rw"),
+        }
+    }
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, cycle) = match self {
+            Anomaly::G1a {
+                reader,
+                writer,
+                key,
+                value,
+            } => {
+                return write!(
+                    f,
+                    "G1a: op {reader} read key {key} = {value} written by aborted op {writer}"
+                )
+            }
+            Anomaly::G1b {
+                reader,
+                writer,
+                key,
+                value,
+            } => {
+                return write!(
+                f,
+                "G1b: op {reader} read key {key} = {value}, an intermediate write of op {writer}"
+            )
+            }
+            Anomaly::G0 { cycle } => ("G0", cycle),
+            Anomaly::G1c { cycle } => ("G1c", cycle),
+            Anomaly::G2 { cycle } => ("G2", cycle),
+        };
+        write!(f, "{name}:")?;
+        for edge in cycle {
+            write!(
+                f,
+                " op {} -{}(key {})->",
+                edge.from, edge.dependency, edge.key
+            )?;
+        }
+        match cycle.first() {
+            Some(edge) => write!(f, " op {}", edge.from),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsistencyModel {
+    ReadUncommitted,
+    ReadCommitted,
+    Serializable,
+}
+
+impl ConsistencyModel {
+    pub fn proscribes(self, kind: AnomalyKind) -> bool {
+        match self {
+            ConsistencyModel::ReadUncommitted => kind == AnomalyKind::G0,
+            ConsistencyModel::ReadCommitted => kind != AnomalyKind::G2,
+            ConsistencyModel::Serializable => true,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub anomalies: Vec<Anomaly>,
+}
+
+impl Report {
+    pub fn violations(&self, model: ConsistencyModel) -> impl Iterator<Item = &Anomaly> {
+        self.anomalies
+            .iter()
+            .filter(move |anomaly| model.proscribes(anomaly.kind()))
+    }
+
+    pub fn is_valid(&self, model: ConsistencyModel) -> bool {
+        self.violations(model).next().is_none()
+    }
+}
+
+struct Write {
+    op: usize,
+    is_final: bool,
+}
+
+#[derive(Default)]
+struct Graph {
+    edges: HashMap<usize, Vec<Edge>>,
+    seen: HashSet<(usize, usize, Dependency)>,
+}
+
+impl Graph {
+    fn add(&mut self, from: usize, to: usize, dependency: Dependency, key: usize) {
+        if from == to || !self.seen.insert((from, to, dependency)) {
+            return;
+        }
+        self.edges.entry(from).or_default().push(Edge {
+            from,
+            to,
+            dependency,
+            key,
+        });
+    }
+
+    fn out(&self, node: usize) -> &[Edge] {
+        self.edges.get(&node).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// iterative Tarjan, so long dependency chains cannot overflow the stack
+    fn strongly_connected_components(&self) -> Vec<HashSet<usize>> {
+        let mut index = HashMap::new();
+        let mut low = HashMap::new();
+        let mut on_stack = HashSet::new();
+        let mut stack = Vec::new();
+        let mut components = Vec::new();
+        let mut next_index = 0;
+
+        let mut roots = self.edges.keys().copied().collect::<Vec<_>>();
+        roots.sort_unstable();
+        for root in roots {
+            if index.contains_key(&root) {
+                continue;
+            }
+            let mut work = vec![(root, 0)];
+            while let Some((node, child)) = work.pop() {
+                if child == 0 {
+                    index.insert(node, next_index);
+                    low.insert(node, next_index);
+                    next_index += 1;
+                    stack.push(node);
+                    on_stack.insert(node);
+                }
+                if let Some(edge) = self.out(node).get(child) {
+                    work.push((node, child + 1));
+                    if !index.contains_key(&edge.to) {
+                        work.push((edge.to, 0));
+                    } else if on_stack.contains(&edge.to) {
+                        low.insert(node, low[&node].min(index[&edge.to]));
+                    }
+                    continue;
+                }
+                if low[&node] == index[&node] {
+                    let mut component = HashSet::new();
+                    while let Some(member) = stack.pop() {
+                        on_stack.remove(&member);
+                        component.insert(member);
+                        if member == node {
+                            break;
+                        }
+                    }
+                    components.push(component);
+                }
+                if let Some(&(parent, _)) = work.last() {
+                    low.insert(parent, low[&parent].min(low[&node]));
+                }
+            }
+        }
+        components
+    }
+
+    /// shortest cycle inside `component` that takes at least one `through` edge and otherwise
+    /// only follows `allowed` edges
+    fn shortest_cycle(
+        &self,
+        component: &HashSet<usize>,
+        through: Dependency,
+        allowed: &[Dependency],
+    ) -> Option<Vec<Edge>> {
+        let mut best: Option<Vec<Edge>> = None;
+        let mut starts = component
+            .iter()
+            .flat_map(|&node| self.out(node))
+            .filter(|edge| edge.dependency == through && component.contains(&edge.to))
+            .collect::<Vec<_>>();
+        starts.sort_unstable_by_key(|edge| (edge.from, edge.to));
+        for start in starts {
+            let Some(path) = self.shortest_path(component, start.to, start.from, allowed) else {
+                continue;
+            };
+            if best.as_ref().is_none_or(|best| path.len() + 1 < best.len()) {
+                let mut cycle = vec![*start];
+                cycle.extend(path);
+                best = Some(cycle);
+            }
+        }
+        best
+    }
+
+    fn shortest_path(
+        &self,
+        component: &HashSet<usize>,
+        from: usize,
+        to: usize,
+        allowed: &[Dependency],
+    ) -> Option<Vec<Edge>> {
+        let mut parent: HashMap<usize, Edge> = HashMap::new();
+        let mut queue = VecDeque::from([from]);
+        let mut visited = HashSet::from([from]);
+        while let Some(node) = queue.pop_front() {
+            if node == to {
+                let mut path = Vec::new();
+                let mut current = to;
+                while current != from {
+                    let edge = parent[&current];
+                    path.push(edge);
+                    current = edge.from;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for edge in self.out(node) {
+                if allowed.contains(&edge.dependency)
+                    && component.contains(&edge.to)
+                    && visited.insert(edge.to)
+                {
+                    parent.insert(edge.to, *edge);
+                    queue.push_back(edge.to);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// values each key had when the transaction first touched it, for keys read before written
+fn external_reads(txn: &[MicroOp]) -> Vec<(usize, Option<usize>)> {
+    let mut touched = HashSet::new();
+    txn.iter()
+        .filter_map(|micro_op| match *micro_op {
+            MicroOp::Read { key, value } if touched.insert(key) => Some((key, value)),
+            MicroOp::Read { .. } => None,
+            MicroOp::Write { key, .. } => {
+                touched.insert(key);
+                None
+            }
+        })
+        .collect()
+}
+
+fn final_writes(txn: &[MicroOp]) -> HashMap<usize, usize> {
+    txn.iter()
+        .filter_map(|micro_op| match *micro_op {
+            MicroOp::Write { key, value } => Some((key, value)),
+            MicroOp::Read { .. } => None,
+        })
+        .collect()
+}
+
+/// Checks a history and reports every anomaly found, regardless of consistency model.
+/// Operation indices in the report refer to positions in `history`.
+pub fn check(history: &[Op]) -> Report {
+    let mut writes: HashMap<(usize, usize), Write> = HashMap::new();
+    let mut aborted: HashMap<(usize, usize), usize> = HashMap::new();
+    let mut writers_of: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (idx, op) in history.iter().enumerate() {
+        match op.kind {
+            // indeterminate transactions may have committed, so their writes can be observed
+            OpKind::Ok | OpKind::Info => {
+                let finals = final_writes(&op.txn);
+                for micro_op in &op.txn {
+                    if let MicroOp::Write { key, value } = *micro_op {
+                        let is_final = finals[&key] == value;
+                        writes
+                            .entry((key, value))
+                            .or_insert(Write { op: idx, is_final });
+                    }
+                }
+                for key in finals.into_keys() {
+                    writers_of.entry(key).or_default().push(idx);
+                }
+            }
+            OpKind::Fail => {
+                for micro_op in &op.txn {
+                    if let MicroOp::Write { key, value } = *micro_op {
+                        aborted.insert((key, value), idx);
+                    }
+                }
+            }
+            OpKind::Invoke => (),
+        }
+    }
+
+    let committed = || {
+        history
+            .iter()
+            .enumerate()
+            .filter(|(_, op)| op.kind == OpKind::Ok)
+    };
+
+    let mut report = Report::default();
+    let mut graph = Graph::default();
+    let mut readers: HashMap<(usize, Option<usize>), Vec<usize>> = HashMap::new();
+    for (idx, op) in committed() {
+        for (key, value) in external_reads(&op.txn) {
+            readers.entry((key, value)).or_default().push(idx);
+            let Some(value) = value else {
+                continue;
+            };
+            if let Some(&writer) = aborted.get(&(key, value)) {
+                report.anomalies.push(Anomaly::G1a {
+                    reader: idx,
+                    writer,
+                    key,
+                    value,
+                });
+            } else if let Some(write) = writes.get(&(key, value)) {
+                if !write.is_final && write.op != idx {
+                    report.anomalies.push(Anomaly::G1b {
+                        reader: idx,
+                        writer: write.op,
+                        key,
+                        value,
+                    });
+                } else {
+                    graph.add(write.op, idx, Dependency::Wr, key);
+                }
+            }
+        }
+    }
+
+    // a transaction that reads a version and then overwrites it installs the next version
+    for (idx, op) in committed() {
+        let finals = final_writes(&op.txn);
+        for (key, value) in external_reads(&op.txn) {
+            if !finals.contains_key(&key) {
+                continue;
+            }
+            if let Some(write) = value.and_then(|value| writes.get(&(key, value))) {
+                graph.add(write.op, idx, Dependency::Ww, key);
+            }
+            for &reader in readers.get(&(key, value)).into_iter().flatten() {
+                graph.add(reader, idx, Dependency::Rw, key);
+            }
+        }
+    }
+
+    // the initial nil version precedes every write of the key
+    for (&(key, value), key_readers) in &readers {
+        if value.is_some() {
+            continue;
+        }
+        for &writer in writers_of.get(&key).into_iter().flatten() {
+            for &reader in key_readers {
+                graph.add(reader, writer, Dependency::Rw, key);
+            }
+        }
+    }
+
+    let searches = [
+        (Dependency::Ww, &[Dependency::Ww][..]),
+        (Dependency::Wr, &[Dependency::Ww, Dependency::Wr][..]),
+        (
+            Dependency::Rw,
+            &[Dependency::Ww, Dependency::Wr, Dependency::Rw][..],
+        ),
+    ];
+    for component in graph.strongly_connected_components() {
+        if component.len() < 2 {
+            continue;
+        }
+        for (through, allowed) in searches {
+            if let Some(cycle) = graph.shortest_cycle(&component, through, allowed) {
+                report.anomalies.push(match through {
+                    Dependency::Ww => Anomaly::G0 { cycle },
+                    Dependency::Wr => Anomaly::G1c { cycle },
+                    Dependency::Rw => Anomaly::G2 { cycle },
+                });
+            }
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn kinds(history: &str) -> Vec<AnomalyKind> {
+        let history: Vec<Op> = serde_json::from_str(history).unwrap();
+        check(&history)
+            .anomalies
+            .iter()
+            .map(Anomaly::kind)
+            .collect()
+    }
+
+    #[test]
+    fn serial_history_has_no_anomalies() {
+        let history = r#"[
+            {"process": 0, "type": "ok", "value": [["r", 1, null], ["w", 1, 1]]},
+            {"process": 1, "type": "ok", "value": [["r", 1, 1], ["w", 1, 2], ["w", 2, 1]]},
+            {"process": 0, "type": "ok", "value": [["r", 1, 2], ["r", 2, 1]]}
+        ]"#;
+        assert_eq!(kinds(history), vec![]);
+    }
+
+    #[test]
+    fn aborted_and_intermediate_reads() {
+        let history = r#"[
+            {"process": 0, "type": "fail", "value": [["w", 1, 1]]},
+            {"process": 1, "type": "ok", "value": [["w", 2, 1], ["w", 2, 2]]},
+            {"process": 2, "type": "ok", "value": [["r", 1, 1], ["r", 2, 1]]}
+        ]"#;
+        assert_eq!(kinds(history), vec![AnomalyKind::G1a, AnomalyKind::G1b]);
+    }
+
+    #[test]
+    fn write_cycle() {
+        let history = r#"[
+            {"process": 0, "type": "ok", "value": [["w", 1, 1], ["r", 2, 2], ["w", 2, 1]]},
+            {"process": 1, "type": "ok", "value": [["r", 1, 1], ["w", 1, 2], ["w", 2, 2]]}
+        ]"#;
+        let found = kinds(history);
+        assert!(found.contains(&AnomalyKind::G0));
+        assert!(found.contains(&AnomalyKind::G1c));
+    }
+
+    #[test]
+    fn blind_writes_have_no_version_order() {
+        // the same interleaving of writes, once blind and once reading first
+        let blind = r#"[
+            {"process": 0, "type": "ok", "value": [["w", 1, 1], ["w", 2, 1]]},
+            {"process": 1, "type": "ok", "value": [["w", 1, 2], ["w", 2, 2]]},
+            {"process": 2, "type": "ok", "value": [["r", 1, 1], ["r", 2, 2]]}
+        ]"#;
+        assert_eq!(kinds(blind), vec![]);
+
+        let read_first = r#"[
+            {"process": 0, "type": "ok", "value": [["r", 1, 2], ["w", 1, 1], ["r", 2, null], ["w", 2, 1]]},
+            {"process": 1, "type": "ok", "value": [["r", 1, null], ["w", 1, 2], ["r", 2, 1], ["w", 2, 2]]}
+        ]"#;
+        assert!(kinds(read_first).contains(&AnomalyKind::G0));
+    }
+
+    #[test]
+    fn circular_information_flow() {
+        let history = r#"[
+            {"process": 0, "type": "ok", "value": [["w", 1, 1], ["r", 2, 1]]},
+            {"process": 1, "type": "ok", "value": [["w", 2, 1], ["r", 1, 1]]}
+        ]"#;
+        assert_eq!(kinds(history), vec![AnomalyKind::G1c]);
+    }
+
+    #[test]
+    fn write_skew() {
+        let history: Vec<Op> = serde_json::from_str(
+            r#"[
+            {"process": 0, "type": "ok", "value": [["r", 1, null], ["w", 2, 1]]},
+            {"process": 1, "type": "ok", "value": [["r", 2, null], ["w", 1, 1]]}
+        ]"#,
+        )
+        .unwrap();
+        let report = check(&history);
+        let kinds = report
+            .anomalies
+            .iter()
+            .map(Anomaly::kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec![AnomalyKind::G2]);
+        assert!(report.is_valid(ConsistencyModel::ReadCommitted));
+        assert!(!report.is_valid(ConsistencyModel::Serializable));
+        assert_eq!(
+            report.anomalies[0].to_string(),
+            "G2: op 0 -rw(key 1)-> op 1 -rw(key 2)-> op 0"
+        );
+    }
+}
@@ -0,0 +1,387 @@
+//! Linearizability checking for read/write/cas register histories, in the style of the
+//! Wing & Gong / Lowe search used by Knossos.
+//!
+//! Histories may target a single register (`"value": 3`, `"value": [1, 2]` for cas) or a
+//! key-value store of independent registers, as in the `lin-kv` workload (`"value": [k, 3]`,
+//! `"value": [k, [1, 2]]` for cas). Keys are checked independently, since a history is
+//! linearizable iff the sub-history of each key is.
+//!
+//! Failed operations are dropped. Indeterminate (`info`) and never-completed operations may take
+//! effect at any point after their invocation, or not at all.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::OpKind;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Function {
+    Read,
+    Write,
+    Cas,
+}
+
+/// A single history entry, deserializable from
+/// `{"process": 0, "type": "invoke", "f": "write", "value": [1, 3]}`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Op {
+    pub process: usize,
+    #[serde(rename = "type")]
+    pub kind: OpKind,
+    pub f: Function,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterOp {
+    Read(Option<i64>),
+    Write(i64),
+    Cas(i64, i64),
+}
+
+impl fmt::Display for RegisterOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegisterOp::Read(Some(value)) => write!(f, "read {value}"),
+            RegisterOp::Read(None) => write!(f, "read nil"),
+            RegisterOp::Write(value) => write!(f, "write {value}"),
+            RegisterOp::Cas(from, to) => write!(f, "cas {from} -> {to}"),
+        }
+    }
+}
+
+/// An operation on one register, with the history indices of its invocation and completion.
+/// Indeterminate operations have no completion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Call {
+    pub process: usize,
+    pub op: RegisterOp,
+    pub invoke: usize,
+    pub complete: Option<usize>,
+}
+
+/// The deepest point the search reached before every remaining order was ruled out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Counterexample {
+    pub key: Option<i64>,
+    /// operations in the order they were linearized
+    pub linearized: Vec<Call>,
+    /// register value after applying `linearized`
+    pub state: Option<i64>,
+    /// operations that could have taken effect next, none of which is legal from `state`
+    pub stuck: Vec<Call>,
+}
+
+impl fmt::Display for Counterexample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.key {
+            Some(key) => writeln!(f, "key {key} is not linearizable")?,
+            None => writeln!(f, "register is not linearizable")?,
+        }
+        writeln!(f, "longest linearizable prefix:")?;
+        for call in &self.linearized {
+            writeln!(f, "  {}", DisplayCall(call))?;
+        }
+        match self.state {
+            Some(value) => writeln!(f, "leaves the register at {value}, but none of:")?,
+            None => writeln!(f, "leaves the register at nil, but none of:")?,
+        }
+        for call in &self.stuck {
+            writeln!(f, "  {}", DisplayCall(call))?;
+        }
+        write!(f, "can take effect next")
+    }
+}
+
+struct DisplayCall<'a>(&'a Call);
+
+impl<'a> fmt::Display for DisplayCall<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Call {
+            process,
+            op,
+            invoke,
+            complete,
+        } = self.0;
+        match complete {
+            Some(complete) => write!(f, "process {process}: {op} (ops {invoke}..{complete})"),
+            None => write!(f, "process {process}: {op} (op {invoke}, indeterminate)"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Linearizable,
+    NotLinearizable(Box<Counterexample>),
+    /// the time budget ran out before the search finished
+    Unknown,
+}
+
+/// Checks every key in `history`, giving up with [`Outcome::Unknown`] once `budget` elapses.
+pub fn check(history: &[Op], budget: Duration) -> anyhow::Result<Outcome> {
+    let deadline = Instant::now() + budget;
+    let mut outcome = Outcome::Linearizable;
+    for (key, calls) in calls_by_key(history)? {
+        match search(key, &calls, deadline) {
+            Outcome::Linearizable => (),
+            Outcome::Unknown => outcome = Outcome::Unknown,
+            not_linearizable => return Ok(not_linearizable),
+        }
+    }
+    Ok(outcome)
+}
+
+/// pairs invocations with completions and splits the result per key
+fn calls_by_key(history: &[Op]) -> anyhow::Result<BTreeMap<Option<i64>, Vec<Call>>> {
+    let mut pending: HashMap<usize, usize> = HashMap::new();
+    let mut calls: BTreeMap<Option<i64>, Vec<Call>> = BTreeMap::new();
+    let mut push = |invoke: usize, complete: Option<&Op>, complete_idx: Option<usize>| {
+        let op = &history[invoke];
+        let (key, register_op) = parse(op.f, complete.unwrap_or(op))
+            .with_context(|| format!("failed to parse history entry {invoke}: {:?}", op.value))?;
+        calls.entry(key).or_default().push(Call {
+            process: op.process,
+            op: register_op,
+            invoke,
+            complete: complete_idx,
+        });
+        anyhow::Ok(())
+    };
+    for (idx, op) in history.iter().enumerate() {
+        if op.kind == OpKind::Invoke {
+            if pending.insert(op.process, idx).is_some() {
+                bail!(
+                    "process {} invoked op {idx} while another op was pending",
+                    op.process
+                );
+            }
+            continue;
+        }
+        let invoke = pending
+            .remove(&op.process)
+            .ok_or_else(|| anyhow!("op {idx} completes nothing for process {}", op.process))?;
+        match op.kind {
+            OpKind::Ok => push(invoke, Some(op), Some(idx))?,
+            OpKind::Info if history[invoke].f != Function::Read => push(invoke, None, None)?,
+            _ => (),
+        }
+    }
+    for invoke in pending.into_values() {
+        if history[invoke].f != Function::Read {
+            push(invoke, None, None)?;
+        }
+    }
+    for key_calls in calls.values_mut() {
+        key_calls.sort_unstable_by_key(|call| call.invoke);
+    }
+    Ok(calls)
+}
+
+fn parse(f: Function, op: &Op) -> anyhow::Result<(Option<i64>, RegisterOp)> {
+    let int = |value: &Value| {
+        value
+            .as_i64()
+            .ok_or_else(|| anyhow!("expected an integer, got {value}"))
+    };
+    let pair = |value: &Value| match value.as_array().map(Vec::as_slice) {
+        Some([first, second]) => Ok((first.clone(), second.clone())),
+        _ => Err(anyhow!("expected a pair, got {value}")),
+    };
+    let (key, value) = match (f, &op.value) {
+        (Function::Read | Function::Write, Value::Array(_)) => {
+            let (key, value) = pair(&op.value)?;
+            (Some(int(&key)?), value)
+        }
+        (Function::Cas, value) if matches!(pair(value), Ok((_, Value::Array(_)))) => {
+            let (key, value) = pair(value)?;
+            (Some(int(&key)?), value)
+        }
+        (_, value) => (None, value.clone()),
+    };
+    let register_op = match f {
+        Function::Read if value.is_null() => RegisterOp::Read(None),
+        Function::Read => RegisterOp::Read(Some(int(&value)?)),
+        Function::Write => RegisterOp::Write(int(&value)?),
+        Function::Cas => {
+            let (from, to) = pair(&value)?;
+            RegisterOp::Cas(int(&from)?, int(&to)?)
+        }
+    };
+    Ok((key, register_op))
+}
+
+fn step(state: Option<i64>, op: RegisterOp) -> Option<Option<i64>> {
+    match op {
+        RegisterOp::Read(value) if value == state => Some(state),
+        RegisterOp::Read(_) => None,
+        RegisterOp::Write(value) => Some(Some(value)),
+        RegisterOp::Cas(from, to) if state == Some(from) => Some(Some(to)),
+        RegisterOp::Cas(..) => None,
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Bitset(Vec<u64>);
+
+impl Bitset {
+    fn new(len: usize) -> Self {
+        Self(vec![0; len.div_ceil(64)])
+    }
+
+    fn contains(&self, idx: usize) -> bool {
+        self.0[idx / 64] & (1 << (idx % 64)) != 0
+    }
+
+    fn insert(&mut self, idx: usize) {
+        self.0[idx / 64] |= 1 << (idx % 64);
+    }
+
+    fn remove(&mut self, idx: usize) {
+        self.0[idx / 64] &= !(1 << (idx % 64));
+    }
+}
+
+/// calls that may be linearized next: those invoked before every remaining call completed
+fn candidates(calls: &[Call], linearized: &Bitset) -> Vec<usize> {
+    let remaining = || (0..calls.len()).filter(|&idx| !linearized.contains(idx));
+    let horizon = remaining()
+        .filter_map(|idx| calls[idx].complete)
+        .min()
+        .unwrap_or(usize::MAX);
+    remaining()
+        .filter(|&idx| calls[idx].invoke < horizon)
+        .collect()
+}
+
+fn search(key: Option<i64>, calls: &[Call], deadline: Instant) -> Outcome {
+    let mut required = calls.iter().filter(|call| call.complete.is_some()).count();
+    let mut linearized = Bitset::new(calls.len());
+    let mut visited = HashSet::new();
+    let mut state = None;
+    let mut path: Vec<(usize, Option<i64>)> = Vec::new();
+    let mut frames = vec![candidates(calls, &linearized)];
+    let mut deepest = Counterexample {
+        key,
+        linearized: Vec::new(),
+        state,
+        stuck: candidates(calls, &linearized)
+            .into_iter()
+            .map(|idx| calls[idx])
+            .collect(),
+    };
+    let mut steps = 0u64;
+
+    loop {
+        if required == 0 {
+            return Outcome::Linearizable;
+        }
+        steps += 1;
+        if steps.is_multiple_of(1024) && Instant::now() > deadline {
+            return Outcome::Unknown;
+        }
+        let Some(frame) = frames.last_mut() else {
+            break;
+        };
+        let Some(next) = frame.pop() else {
+            frames.pop();
+            if let Some((prev, prev_state)) = path.pop() {
+                linearized.remove(prev);
+                state = prev_state;
+                if calls[prev].complete.is_some() {
+                    required += 1;
+                }
+            }
+            continue;
+        };
+        let Some(next_state) = step(state, calls[next].op) else {
+            continue;
+        };
+        linearized.insert(next);
+        if !visited.insert((linearized.clone(), next_state)) {
+            linearized.remove(next);
+            continue;
+        }
+        path.push((next, state));
+        state = next_state;
+        if calls[next].complete.is_some() {
+            required -= 1;
+        }
+        let next_frame = candidates(calls, &linearized);
+        if path.len() > deepest.linearized.len() {
+            deepest = Counterexample {
+                key,
+                linearized: path.iter().map(|&(idx, _)| calls[idx]).collect(),
+                state,
+                stuck: next_frame.iter().map(|&idx| calls[idx]).collect(),
+            };
+        }
+        frames.push(next_frame);
+    }
+
+    Outcome::NotLinearizable(Box::new(deepest))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check_str(history: &str) -> Outcome {
+        let history: Vec<Op> = serde_json::from_str(history).unwrap();
+        check(&history, Duration::from_secs(5)).unwrap()
+    }
+
+    #[test]
+    fn concurrent_register_ops() {
+        let history = r#"[
+            {"process": 0, "type": "invoke", "f": "write", "value": 1},
+            {"process": 1, "type": "invoke", "f": "read", "value": null},
+            {"process": 1, "type": "ok", "f": "read", "value": 1},
+            {"process": 2, "type": "invoke", "f": "cas", "value": [1, 2]},
+            {"process": 0, "type": "ok", "f": "write", "value": 1},
+            {"process": 2, "type": "ok", "f": "cas", "value": [1, 2]},
+            {"process": 1, "type": "invoke", "f": "read", "value": null},
+            {"process": 1, "type": "ok", "f": "read", "value": 2}
+        ]"#;
+        assert_eq!(check_str(history), Outcome::Linearizable);
+    }
+
+    #[test]
+    fn stale_read() {
+        let history = r#"[
+            {"process": 0, "type": "invoke", "f": "write", "value": [5, 1]},
+            {"process": 0, "type": "ok", "f": "write", "value": [5, 1]},
+            {"process": 0, "type": "invoke", "f": "write", "value": [5, 2]},
+            {"process": 0, "type": "ok", "f": "write", "value": [5, 2]},
+            {"process": 1, "type": "invoke", "f": "read", "value": [5, null]},
+            {"process": 1, "type": "ok", "f": "read", "value": [5, 1]}
+        ]"#;
+        let Outcome::NotLinearizable(counterexample) = check_str(history) else {
+            panic!("stale read should not be linearizable");
+        };
+        assert_eq!(counterexample.key, Some(5));
+        assert_eq!(counterexample.state, Some(2));
+        assert_eq!(counterexample.stuck[0].op, RegisterOp::Read(Some(1)));
+    }
+
+    #[test]
+    fn indeterminate_write_may_take_effect_late() {
+        let history = r#"[
+            {"process": 0, "type": "invoke", "f": "write", "value": [1, 1]},
+            {"process": 0, "type": "info", "f": "write", "value": [1, 1]},
+            {"process": 1, "type": "invoke", "f": "read", "value": [1, null]},
+            {"process": 1, "type": "ok", "f": "read", "value": [1, null]},
+            {"process": 1, "type": "invoke", "f": "cas", "value": [1, [1, 3]]},
+            {"process": 1, "type": "fail", "f": "cas", "value": [1, [1, 3]]},
+            {"process": 1, "type": "invoke", "f": "read", "value": [1, null]},
+            {"process": 1, "type": "ok", "f": "read", "value": [1, 1]}
+        ]"#;
+        assert_eq!(check_str(history), Outcome::Linearizable);
+    }
+}
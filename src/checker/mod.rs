@@ -0,0 +1,16 @@
+//! Offline checkers for histories recorded against the nodes in this crate.
+
+use serde::Deserialize;
+
+pub mod elle;
+pub mod linearizability;
+
+/// The `type` of a history entry, as recorded by Jepsen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OpKind {
+    Invoke,
+    Ok,
+    Fail,
+    Info,
+}
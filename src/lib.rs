@@ -2,7 +2,17 @@ use anyhow::{anyhow, Context};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
+use std::fmt::Display;
 use std::io::{BufRead, BufReader};
+use std::str::FromStr;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub mod checker;
+#[cfg(any(test, feature = "simulation"))]
+pub mod simulation;
 
 #[derive(Deserialize)]
 pub struct InMessage<Payload> {
@@ -59,7 +69,7 @@ impl<'a, Payload> OutMessage<'a, Payload> {
 }
 
 impl PartialInMessage {
-    pub fn to_out_msg<Payload>(&self, payload: Payload) -> OutMessage<Payload> {
+    pub fn to_out_msg<Payload>(&self, payload: Payload) -> OutMessage<'_, Payload> {
         OutMessage {
             src: &self.dst,
             dst: &self.src,
@@ -80,6 +90,87 @@ pub struct Body<Payload> {
     pub payload: Payload,
 }
 
+/// Error codes defined by the Maelstrom protocol, sent as the `code` of an `error` body.
+/// Services may send codes of their own, from 1000 up, which are kept as `Other`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "usize", from = "usize")]
+pub enum ErrorCode {
+    Timeout,
+    NodeNotFound,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed,
+    TxnConflict,
+    Other(usize),
+}
+
+impl From<ErrorCode> for usize {
+    fn from(value: ErrorCode) -> Self {
+        match value {
+            ErrorCode::Timeout => 0,
+            ErrorCode::NodeNotFound => 1,
+            ErrorCode::NotSupported => 10,
+            ErrorCode::TemporarilyUnavailable => 11,
+            ErrorCode::MalformedRequest => 12,
+            ErrorCode::Crash => 13,
+            ErrorCode::Abort => 14,
+            ErrorCode::KeyDoesNotExist => 20,
+            ErrorCode::KeyAlreadyExists => 21,
+            ErrorCode::PreconditionFailed => 22,
+            ErrorCode::TxnConflict => 30,
+            ErrorCode::Other(code) => code,
+        }
+    }
+}
+
+impl From<usize> for ErrorCode {
+    fn from(value: usize) -> Self {
+        match value {
+            0 => Self::Timeout,
+            1 => Self::NodeNotFound,
+            10 => Self::NotSupported,
+            11 => Self::TemporarilyUnavailable,
+            12 => Self::MalformedRequest,
+            13 => Self::Crash,
+            14 => Self::Abort,
+            20 => Self::KeyDoesNotExist,
+            21 => Self::KeyAlreadyExists,
+            22 => Self::PreconditionFailed,
+            30 => Self::TxnConflict,
+            code => Self::Other(code),
+        }
+    }
+}
+
+/// Reads the setting `name` from the environment, if it is set.
+pub fn env_config<T>(name: &str) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|err| anyhow!("invalid value {value:?} for {name}: {err}")),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("failed to read {name}")),
+    }
+}
+
+/// The splitmix64 finalizer, a bijection on `u64` that spreads consecutive inputs over the
+/// whole range.
+pub fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
 pub struct SerializableIterator<'a, T>(Box<RefCell<dyn Iterator<Item = T> + 'a>>)
 where
     T: Serialize;
@@ -104,7 +195,7 @@ where
     where
         S: serde::Serializer,
     {
-        serializer.collect_seq(self.0.borrow_mut().into_iter())
+        serializer.collect_seq(&mut *self.0.borrow_mut())
     }
 }
 
@@ -125,13 +216,6 @@ enum InitOkPayload {
     InitOk,
 }
 
-#[derive(Deserialize)]
-#[serde(untagged)]
-enum InitOrRegular<P> {
-    Init(InMessage<InitPayload>),
-    Regular(InMessage<P>),
-}
-
 pub struct MessageSerializer<W>
 where
     W: std::io::Write + Send + Sync,
@@ -166,12 +250,129 @@ where
     }
 }
 
+/// A client of one of Maelstrom's key-value services that keeps at most one request in flight,
+/// together with what it is for. A request is forgotten once it is answered or expires, so
+/// replies that arrive after that are recognized as stale.
+pub struct StoreClient<T> {
+    node_id: String,
+    service: &'static str,
+    timeout: Duration,
+    request: Option<StoreRequest<T>>,
+}
+
+pub struct StoreRequest<T> {
+    pub msg_id: usize,
+    pub deadline: Instant,
+    /// what the request is for, with the clients waiting on it
+    pub purpose: T,
+}
+
+impl<T> StoreClient<T> {
+    pub fn new(node_id: String, service: &'static str, timeout: Duration) -> Self {
+        Self {
+            node_id,
+            service,
+            timeout,
+            request: None,
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.request.is_some()
+    }
+
+    /// sends `payload` to the service, replacing the request in flight, if any
+    pub fn send<W, P>(
+        &mut self,
+        serializer: &mut MessageSerializer<W>,
+        payload: P,
+        purpose: T,
+    ) -> anyhow::Result<()>
+    where
+        W: std::io::Write + Send + Sync,
+        P: Serialize,
+    {
+        self.request = Some(StoreRequest {
+            msg_id: serializer.msg_id(),
+            deadline: Instant::now() + self.timeout,
+            purpose,
+        });
+        let mut out_msg = OutMessage::new(&self.node_id, self.service, None, payload);
+        serializer
+            .send(&mut out_msg)
+            .with_context(|| format!("failed to serialize {} request", self.service))
+    }
+
+    /// takes the request a reply answers, or returns `None` if the reply is stale
+    pub fn take_reply(&mut self, in_reply_to: Option<usize>) -> Option<StoreRequest<T>> {
+        match self.request.take() {
+            Some(request) if Some(request.msg_id) == in_reply_to => Some(request),
+            request => {
+                self.request = request;
+                None
+            }
+        }
+    }
+
+    /// puts back a request taken by [`StoreClient::take_reply`] whose reply did not answer it,
+    /// leaving it to expire as if the reply had been lost
+    pub fn restore(&mut self, request: StoreRequest<T>) {
+        self.request = Some(request);
+    }
+
+    /// takes the request in flight if it went unanswered past its deadline
+    pub fn take_expired(&mut self) -> Option<StoreRequest<T>> {
+        match self.request.take() {
+            Some(request) if request.deadline <= Instant::now() => {
+                log::warn!("{} did not reply to msg {}", self.service, request.msg_id);
+                Some(request)
+            }
+            request => {
+                self.request = request;
+                None
+            }
+        }
+    }
+}
+
+/// Runs on a separate thread until shut down through `rx`, calling `expire` on `state` every
+/// `sleep_time` to fail the requests whose store stopped replying.
+pub fn expire_store_requests<S, W, F>(
+    state: Arc<Mutex<S>>,
+    serializer: Arc<Mutex<MessageSerializer<W>>>,
+    rx: Receiver<bool>,
+    sleep_time: Duration,
+    mut expire: F,
+) -> anyhow::Result<()>
+where
+    W: std::io::Write + Send + Sync,
+    F: FnMut(&mut S, &mut MessageSerializer<W>) -> anyhow::Result<()>,
+{
+    while rx.try_recv().is_err() {
+        thread::sleep(sleep_time);
+        let mut state = state
+            .lock()
+            .map_err(|_| anyhow!("failed to acquire lock for store state"))?;
+        let mut serializer = serializer
+            .lock()
+            .map_err(|_| anyhow!("failed to acquire lock for serializer"))?;
+        expire(&mut state, &mut serializer)?;
+    }
+    Ok(())
+}
+
 pub trait Node<W, P>
 where
     W: std::io::Write + Send + Sync + 'static,
     P: DeserializeOwned,
 {
-    fn new(node_id: String, node_ids: Vec<String>, serializer: MessageSerializer<W>) -> Self;
+    fn new(
+        node_id: String,
+        node_ids: Vec<String>,
+        serializer: MessageSerializer<W>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized;
 
     fn process(&mut self, in_msg: InMessage<P>) -> anyhow::Result<()>
     where
@@ -208,7 +409,7 @@ where
 
     let InitPayload::Init { node_id, node_ids } = payload;
     let neighbors: Vec<String> = node_ids.into_iter().filter(|id| id != &node_id).collect();
-    let mut node: N = Node::new(node_id, neighbors, sender);
+    let mut node: N = Node::new(node_id, neighbors, sender).context("failed to create node")?;
     for line in in_stream {
         let line = line.context("failed to read the next line from input stream")?;
         let msg: InMessage<P> = serde_json::from_str(&line)
@@ -0,0 +1,452 @@
+//! An in-process cluster for testing nodes without the Maelstrom harness.
+//!
+//! Messages a node sends to another node are held in flight until the test delivers or drops
+//! them, in any order, so tests can drive arbitrary network interleavings. Requests to the
+//! `lin-kv` and `seq-kv` services are held in flight the same way, and so are their replies.
+//! Messages sent to anything else are collected as client replies.
+//!
+//! With the `proptest` feature, [`step`] generates random schedules that mix a test's own actions
+//! with deliveries and drops, and [`Cluster::apply`] plays them.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+#[cfg(feature = "proptest")]
+use proptest::{prelude::*, sample::Index};
+
+use crate::{ErrorCode, InMessage, MessageSerializer, Node};
+
+/// A writer whose contents can be drained while a node owns it.
+#[derive(Clone, Default)]
+pub struct SharedWriter(Arc<Mutex<Vec<u8>>>);
+
+impl SharedWriter {
+    /// removes and returns everything written so far
+    pub fn take(&self) -> anyhow::Result<Vec<u8>> {
+        let mut buffer = self
+            .0
+            .lock()
+            .map_err(|_| anyhow!("failed to acquire lock for writer"))?;
+        Ok(std::mem::take(&mut *buffer))
+    }
+}
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .lock()
+            .map_err(|_| std::io::Error::other("failed to acquire lock for writer"))?
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+struct SimulatedNode<N> {
+    node: N,
+    writer: SharedWriter,
+    /// bytes of a message that was only partially written when last drained
+    partial: Vec<u8>,
+}
+
+/// A key-value service answering `read`, `write` and `cas` like Maelstrom's. Every request is
+/// applied as soon as it is delivered, which is linearizable and therefore also a valid `seq-kv`.
+/// A `seq-kv` may also serve stale reads, from the state right after the client's last write.
+#[derive(Default)]
+struct KvService {
+    values: HashMap<String, Value>,
+    /// every state after a write since reads became stale, oldest first
+    history: Option<Vec<HashMap<String, Value>>>,
+    /// the index in `history` of the state after each client's last write
+    last_write: HashMap<String, usize>,
+}
+
+impl KvService {
+    const NAMES: [&'static str; 2] = ["lin-kv", "seq-kv"];
+
+    /// returns the reply body, without msg_id and in_reply_to
+    fn handle(&mut self, client: &str, body: &Value) -> Value {
+        let reply = self.apply(client, body);
+        if let (Some(history), Some("write_ok" | "cas_ok")) =
+            (&mut self.history, reply["type"].as_str())
+        {
+            history.push(self.values.clone());
+            self.last_write
+                .insert(client.to_string(), history.len() - 1);
+        }
+        reply
+    }
+
+    fn apply(&mut self, client: &str, body: &Value) -> Value {
+        let key = body["key"].to_string();
+        let values = match &self.history {
+            Some(history) => &history[self.last_write.get(client).copied().unwrap_or_default()],
+            None => &self.values,
+        };
+        match body["type"].as_str() {
+            Some("read") => match values.get(&key) {
+                Some(value) => json!({ "type": "read_ok", "value": value }),
+                None => kv_error(
+                    ErrorCode::KeyDoesNotExist,
+                    format!("key {key} does not exist"),
+                ),
+            },
+            Some("write") => {
+                self.values.insert(key, body["value"].clone());
+                json!({ "type": "write_ok" })
+            }
+            Some("cas") => match self.values.get_mut(&key) {
+                Some(value) if *value == body["from"] => {
+                    *value = body["to"].clone();
+                    json!({ "type": "cas_ok" })
+                }
+                Some(value) => kv_error(
+                    ErrorCode::PreconditionFailed,
+                    format!("expected {}, but had {value}", body["from"]),
+                ),
+                None if body["create_if_not_exists"] == json!(true) => {
+                    self.values.insert(key, body["to"].clone());
+                    json!({ "type": "cas_ok" })
+                }
+                None => kv_error(
+                    ErrorCode::KeyDoesNotExist,
+                    format!("key {key} does not exist"),
+                ),
+            },
+            _ => kv_error(
+                ErrorCode::NotSupported,
+                format!("unsupported request {body}"),
+            ),
+        }
+    }
+}
+
+fn kv_error(code: ErrorCode, text: String) -> Value {
+    json!({ "type": "error", "code": code, "text": text })
+}
+
+/// One step of a random schedule: an action of the test, or delivering or dropping an in-flight
+/// message.
+#[cfg(feature = "proptest")]
+#[derive(Debug, Clone)]
+pub enum Step<A> {
+    Act(A),
+    Deliver(Index),
+    Drop(Index),
+}
+
+/// steps that are actions, deliveries and drops in equal proportion
+#[cfg(feature = "proptest")]
+pub fn step<A>(action: impl Strategy<Value = A>) -> impl Strategy<Value = Step<A>>
+where
+    A: std::fmt::Debug + Clone,
+{
+    prop_oneof![
+        action.prop_map(Step::Act),
+        any::<Index>().prop_map(Step::Deliver),
+        any::<Index>().prop_map(Step::Drop),
+    ]
+}
+
+/// like [`step`], but over a network that never loses messages
+#[cfg(feature = "proptest")]
+pub fn lossless_step<A>(action: impl Strategy<Value = A>) -> impl Strategy<Value = Step<A>>
+where
+    A: std::fmt::Debug + Clone,
+{
+    prop_oneof![
+        action.prop_map(Step::Act),
+        any::<Index>().prop_map(Step::Deliver),
+    ]
+}
+
+type NodeFactory<N> =
+    Box<dyn Fn(String, Vec<String>, MessageSerializer<SharedWriter>) -> anyhow::Result<N>>;
+
+type MessageFilter = Box<dyn Fn(&Value) -> bool>;
+
+pub struct Cluster<N, P> {
+    nodes: BTreeMap<String, SimulatedNode<N>>,
+    services: BTreeMap<String, KvService>,
+    factory: NodeFactory<N>,
+    in_flight: Vec<Value>,
+    replies: Vec<Value>,
+    /// number of messages delivered so far, by body type
+    delivered: HashMap<String, usize>,
+    /// whether a message is lost as soon as it is sent
+    dropped: Option<MessageFilter>,
+    msg_id: usize,
+    payload: PhantomData<P>,
+}
+
+impl<N, P> Cluster<N, P>
+where
+    N: Node<SharedWriter, P>,
+    P: DeserializeOwned,
+{
+    /// creates nodes `n0` to `n{node_count - 1}`, already initialized
+    pub fn new(node_count: usize) -> anyhow::Result<Self>
+    where
+        N: 'static,
+        P: 'static,
+    {
+        Self::with_factory(node_count, N::new)
+    }
+
+    /// like [`Cluster::new`], but creates each node with `factory` instead of [`Node::new`], for
+    /// nodes that are otherwise configured from the environment
+    pub fn with_factory<F>(node_count: usize, factory: F) -> anyhow::Result<Self>
+    where
+        F: Fn(String, Vec<String>, MessageSerializer<SharedWriter>) -> anyhow::Result<N> + 'static,
+    {
+        let mut cluster = Self {
+            nodes: BTreeMap::new(),
+            services: KvService::NAMES
+                .iter()
+                .map(|name| (name.to_string(), KvService::default()))
+                .collect(),
+            factory: Box::new(factory),
+            in_flight: Vec::new(),
+            replies: Vec::new(),
+            delivered: HashMap::new(),
+            dropped: None,
+            msg_id: 1,
+            payload: PhantomData,
+        };
+        for idx in 0..node_count {
+            let node_id = format!("n{idx}");
+            let simulated = cluster.start(&node_id, node_count)?;
+            cluster.nodes.insert(node_id, simulated);
+        }
+        Ok(cluster)
+    }
+
+    /// shuts `node_id` down and replaces it with a fresh instance, as if its process had been
+    /// restarted; messages already in flight to it are delivered to the new instance
+    pub fn restart(&mut self, node_id: &str) -> anyhow::Result<()> {
+        self.poll()?;
+        let simulated = self.start(node_id, self.nodes.len())?;
+        let old = self
+            .nodes
+            .insert(node_id.to_string(), simulated)
+            .ok_or_else(|| anyhow!("no node named {node_id:?}"))?;
+        old.node
+            .shutdown()
+            .with_context(|| format!("failed to shutdown {node_id}"))
+    }
+
+    pub fn node_ids(&self) -> impl Iterator<Item = &str> {
+        self.nodes.keys().map(String::as_str)
+    }
+
+    /// sends `body` from `client` to `node` and processes it right away, returning its msg_id
+    pub fn request(&mut self, client: &str, node: &str, mut body: Value) -> anyhow::Result<usize> {
+        let msg_id = self.msg_id;
+        self.msg_id += 1;
+        body["msg_id"] = json!(msg_id);
+        let msg = json!({ "src": client, "dest": node, "body": body });
+        self.process(msg)?;
+        Ok(msg_id)
+    }
+
+    /// collects everything the nodes have written so far
+    pub fn poll(&mut self) -> anyhow::Result<()> {
+        let mut sent = Vec::new();
+        for simulated in self.nodes.values_mut() {
+            simulated.partial.extend(simulated.writer.take()?);
+            let end = match simulated.partial.iter().rposition(|&b| b == b'\n') {
+                Some(idx) => idx + 1,
+                None => continue,
+            };
+            let complete = simulated.partial.drain(..end).collect::<Vec<_>>();
+            for line in complete.split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+                let msg: Value = serde_json::from_slice(line)
+                    .context("node wrote a message that is not valid JSON")?;
+                sent.push(msg);
+            }
+        }
+        for msg in sent {
+            let dest = msg["dest"].as_str().unwrap_or_default();
+            if self.dropped.as_ref().is_some_and(|dropped| dropped(&msg)) {
+                continue;
+            }
+            if self.nodes.contains_key(dest) || self.services.contains_key(dest) {
+                self.in_flight.push(msg);
+            } else {
+                self.replies.push(msg);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// delivers the in-flight message at `idx`
+    pub fn deliver(&mut self, idx: usize) -> anyhow::Result<()> {
+        let msg = self.in_flight.swap_remove(idx);
+        let msg_type = msg["body"]["type"].as_str().unwrap_or_default();
+        *self.delivered.entry(msg_type.to_string()).or_default() += 1;
+        self.process(msg)
+    }
+
+    /// how many in-flight messages of `msg_type` were delivered so far
+    pub fn delivered(&self, msg_type: &str) -> usize {
+        self.delivered.get(msg_type).copied().unwrap_or_default()
+    }
+
+    /// drops the in-flight message at `idx`
+    pub fn drop_message(&mut self, idx: usize) {
+        self.in_flight.swap_remove(idx);
+    }
+
+    /// from now on lets `seq-kv` answer each read from the state right after the reading
+    /// client's last write, however many writes of other clients followed
+    pub fn serve_stale_reads(&mut self) {
+        if let Some(service) = self.services.get_mut("seq-kv") {
+            service.history = Some(vec![service.values.clone()]);
+        }
+    }
+
+    /// from now on drops every message a node sends that matches `predicate`, replacing the
+    /// previous one, as a lossy link or a partition would
+    pub fn drop_where<F>(&mut self, predicate: F)
+    where
+        F: Fn(&Value) -> bool + 'static,
+    {
+        self.dropped = Some(Box::new(predicate));
+    }
+
+    /// keeps delivering messages, including those sent by background threads, for `duration`
+    pub fn run_for(&mut self, duration: Duration) -> anyhow::Result<()> {
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline {
+            self.poll()?;
+            while !self.in_flight.is_empty() {
+                self.deliver(0)?;
+                self.poll()?;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+
+    /// runs the cluster until `done` holds, checking it every few milliseconds, and returns
+    /// whether it did before `timeout`
+    pub fn run_until<F>(&mut self, timeout: Duration, mut done: F) -> anyhow::Result<bool>
+    where
+        F: FnMut(&mut Self) -> anyhow::Result<bool>,
+    {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            self.run_for(Duration::from_millis(5))?;
+            if done(self)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// delivers or drops a message for a network step, doing nothing if none is in flight, and
+    /// returns the action of any other step for the test to perform
+    #[cfg(feature = "proptest")]
+    pub fn apply<A>(&mut self, step: Step<A>) -> anyhow::Result<Option<A>> {
+        match step {
+            Step::Act(action) => return Ok(Some(action)),
+            Step::Deliver(idx) if !self.in_flight.is_empty() => {
+                self.deliver(idx.index(self.in_flight.len()))?
+            }
+            Step::Drop(idx) if !self.in_flight.is_empty() => {
+                self.drop_message(idx.index(self.in_flight.len()))
+            }
+            _ => (),
+        }
+        Ok(None)
+    }
+
+    /// runs the cluster until clients got at least `count` replies, and returns them
+    pub fn wait_for_replies(
+        &mut self,
+        count: usize,
+        timeout: Duration,
+    ) -> anyhow::Result<Vec<Value>> {
+        let mut replies = Vec::new();
+        let replied = self.run_until(timeout, |cluster| {
+            replies.extend(cluster.take_replies()?);
+            Ok(replies.len() >= count)
+        })?;
+        if !replied {
+            bail!(
+                "only got {} of {count} replies in {timeout:?}",
+                replies.len()
+            );
+        }
+        Ok(replies)
+    }
+
+    /// removes and returns the messages sent to clients so far
+    pub fn take_replies(&mut self) -> anyhow::Result<Vec<Value>> {
+        self.poll()?;
+        Ok(std::mem::take(&mut self.replies))
+    }
+
+    pub fn shutdown(self) -> anyhow::Result<()> {
+        for (node_id, simulated) in self.nodes {
+            simulated
+                .node
+                .shutdown()
+                .with_context(|| format!("failed to shutdown {node_id}"))?;
+        }
+        Ok(())
+    }
+
+    fn start(&self, node_id: &str, node_count: usize) -> anyhow::Result<SimulatedNode<N>> {
+        let writer = SharedWriter::default();
+        let neighbors = (0..node_count)
+            .map(|idx| format!("n{idx}"))
+            .filter(|id| id != node_id)
+            .collect();
+        let serializer = MessageSerializer::new(writer.clone());
+        let node = (self.factory)(node_id.to_string(), neighbors, serializer)
+            .with_context(|| format!("failed to create {node_id}"))?;
+        Ok(SimulatedNode {
+            node,
+            writer,
+            partial: Vec::new(),
+        })
+    }
+
+    fn process(&mut self, msg: Value) -> anyhow::Result<()> {
+        let dest = msg["dest"].as_str().unwrap_or_default();
+        if let Some(service) = self.services.get_mut(dest) {
+            let client = msg["src"].as_str().unwrap_or_default();
+            let mut body = service.handle(client, &msg["body"]);
+            body["msg_id"] = json!(self.msg_id);
+            body["in_reply_to"] = msg["body"]["msg_id"].clone();
+            self.msg_id += 1;
+            let reply = json!({ "src": dest, "dest": msg["src"], "body": body });
+            self.in_flight.push(reply);
+            return Ok(());
+        }
+        let msg: InMessage<P> =
+            serde_json::from_value(msg).context("failed to deserialize simulated message")?;
+        let simulated = self
+            .nodes
+            .get_mut(&msg.dst)
+            .ok_or_else(|| anyhow!("no node named {:?}", msg.dst))?;
+        simulated.node.process(msg)?;
+        self.poll()
+    }
+}